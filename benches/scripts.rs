@@ -0,0 +1,106 @@
+//! Times scripts end to end. Compare value representations with
+//! `cargo bench` and `cargo bench --features nan_boxing`
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use lox_rs::vm::Vm;
+
+const RUNS: u32 = 10;
+
+/// Name, source and the last line each script prints, checked so a
+/// broken build can't post fast times
+const BENCHES: &[(&str, &str, &str)] = &[
+    ("loop.lox", include_str!("../samples/loop.lox"), "9"),
+    (
+        "counting",
+        r#"
+            var sum = 0;
+            for (var i = 0; i < 1000000; i = i + 1) {
+                sum = sum + i * 2 - 1;
+            }
+            print sum;
+        "#,
+        "999998000000",
+    ),
+    (
+        "fib",
+        r#"
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            print fib(25);
+        "#,
+        "75025",
+    ),
+    (
+        "lists",
+        r#"
+            var list = [];
+            for (var i = 0; i < 100000; i = i + 1) push(list, i);
+            var total = 0;
+            for (var i = 0; i < len(list); i = i + 1) total = total + list[i];
+            print total;
+        "#,
+        "4999950000",
+    ),
+    (
+        "strings",
+        r#"
+            var s = "";
+            for (var i = 0; i < 2000; i = i + 1) s = s + "x";
+            print len(s);
+        "#,
+        "2000",
+    ),
+];
+
+/// Printed output that can still be read after handing it to a Vm
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn main() {
+    // Any filter passed on the command line, as cargo bench does
+    let filter = std::env::args().skip(1).find(|a| !a.starts_with('-'));
+
+    println!(
+        "value size: {} bytes",
+        std::mem::size_of::<lox_rs::value::Value>()
+    );
+    for &(name, src, expected) in BENCHES {
+        if filter.as_deref().is_some_and(|f| !name.contains(f)) {
+            continue;
+        }
+
+        let mut times = Vec::with_capacity(RUNS as usize);
+        for _ in 0..RUNS {
+            let output = Output::default();
+            let mut vm = Vm::new().with_output(Box::new(output.clone()));
+            let start = Instant::now();
+            vm.interpret(src).expect("benchmark script to run");
+            times.push(start.elapsed());
+
+            let printed = String::from_utf8(output.0.take()).expect("utf-8 output");
+            assert_eq!(printed.lines().last(), Some(expected), "{name}");
+        }
+
+        let min = times.iter().min().copied().unwrap_or_default();
+        let mean = times.iter().sum::<Duration>() / RUNS;
+        println!("{name:<12} min {min:>12.3?}  mean {mean:>12.3?}");
+    }
+}
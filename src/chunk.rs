@@ -1,10 +1,30 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
 use crate::{
+    object::{IString, ObjRef},
     util::{join_u8s, split_u16},
     value::Value,
 };
 
-#[derive(Debug, Clone, Copy)]
-pub enum OpCode {
+/// Defines the opcodes along with the table decoding them from bytes,
+/// so the two can't get out of sync
+macro_rules! opcodes {
+    ($($name:ident,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name,)*
+        }
+
+        /// Opcodes indexed by their byte value
+        const OPCODES: &[OpCode] = &[$(OpCode::$name,)*];
+    };
+}
+
+opcodes! {
     Constant,
     ConstantLong,
     Nil,
@@ -21,6 +41,16 @@ pub enum OpCode {
     DefineGlobalLong,
     SetGlobal,
     SetGlobalLong,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    GetSuper,
+    GetSuperLong,
+    GetIndex,
+    SetIndex,
     Equal,
     Greater,
     Less,
@@ -30,33 +60,44 @@ pub enum OpCode {
     Divide,
     Not,
     Negate,
+    Stringify,
     Print,
     Jump,
     JumpIfFalse,
     Loop,
+    Call,
+    Invoke,
+    InvokeLong,
+    SuperInvoke,
+    SuperInvokeLong,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
     Return,
-    Byte(u8),
+    Class,
+    ClassLong,
+    Inherit,
+    BuildList,
+    BuildMap,
+    Method,
+    MethodLong,
+    // Superinstructions produced by the optimizer
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+    SetLocalPop,
+    GetLocalAddConstant,
 }
 
 impl OpCode {
-    pub fn as_byte(&self) -> Option<u8> {
-        match self {
-            Self::Byte(b) => Some(*b),
-            _ => None,
-        }
-    }
-
-    pub fn as_byte_mut(&mut self) -> Option<&mut u8> {
-        match self {
-            Self::Byte(b) => Some(b),
-            _ => None,
-        }
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OPCODES.get(byte as usize).copied()
     }
 }
 
-impl From<u8> for OpCode {
-    fn from(val: u8) -> Self {
-        Self::Byte(val)
+impl From<OpCode> for u8 {
+    fn from(op: OpCode) -> Self {
+        op as u8
     }
 }
 
@@ -72,6 +113,15 @@ impl From<OpCode> for OpLen {
             OpCode::ConstantLong
             | OpCode::DefineGlobalLong
             | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::ClosureLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::GetSuperLong
+            | OpCode::SuperInvokeLong
+            | OpCode::InvokeLong
+            | OpCode::ClassLong
+            | OpCode::MethodLong
             | OpCode::GetLocalLong
             | OpCode::SetLocalLong => OpLen::Long,
             _ => OpLen::Short,
@@ -85,11 +135,53 @@ pub struct LineStart {
     line: usize,
 }
 
-#[derive(Debug)]
+/// Debug info naming a local variable and the code during which its
+/// slot holds it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalInfo {
+    pub name: String,
+    /// Slot relative to the start of the function's frame
+    pub slot: usize,
+    pub start: usize,
+    /// Offset where the variable goes out of scope, None while the
+    /// compiler is still in its scope
+    pub end: Option<usize>,
+}
+
+/// Identity of a constant for deduplication. Numbers are compared by
+/// their bits so constants only merge when they print and compute the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Num(u64),
+    Str(IString),
+    Obj(ObjRef),
+}
+
+impl ConstantKey {
+    fn new(value: &Value) -> Option<Self> {
+        if let Some(n) = value.as_num() {
+            Some(Self::Num(n.to_bits()))
+        } else if let Some(s) = value.as_str() {
+            Some(Self::Str(s))
+        } else {
+            value.as_obj().map(Self::Obj)
+        }
+    }
+}
+
+/// Bytecode for a function. Instructions are an opcode byte followed by
+/// their operand bytes
+#[derive(Debug, Default)]
 pub struct Chunk {
-    code: Vec<OpCode>,
+    code: Vec<u8>,
     constants: Vec<Value>,
+    /// Index of each constant so repeated ones share a slot
+    constant_idx: HashMap<ConstantKey, usize>,
     lines: Vec<LineStart>,
+    locals: Vec<LocalInfo>,
+    /// Where the code of each statement begins and the line the statement
+    /// starts on, ordered by offset
+    statements: Vec<LineStart>,
 }
 
 impl Chunk {
@@ -97,11 +189,14 @@ impl Chunk {
         Self {
             code: Vec::new(),
             constants: Vec::new(),
+            constant_idx: HashMap::new(),
             lines: Vec::new(),
+            locals: Vec::new(),
+            statements: Vec::new(),
         }
     }
 
-    pub fn write_chunk<B: Into<OpCode>>(&mut self, byte: B, line: usize) {
+    pub fn write_chunk<B: Into<u8>>(&mut self, byte: B, line: usize) {
         self.code.push(byte.into());
 
         // See if we're still on the same line
@@ -135,26 +230,217 @@ impl Chunk {
     }
 
     pub fn get_byte(&self, offset: usize) -> Option<u8> {
-        self.get_op(offset).and_then(|o| o.as_byte())
+        self.code.get(offset).copied()
     }
 
     pub fn get_byte_mut(&mut self, offset: usize) -> Option<&mut u8> {
-        self.code.get_mut(offset).and_then(|o| o.as_byte_mut())
+        self.code.get_mut(offset)
     }
 
+    /// None if offset is out of bounds or the byte isn't an opcode
     pub fn get_op(&self, offset: usize) -> Option<OpCode> {
-        self.code.get(offset).copied()
+        self.get_byte(offset).and_then(OpCode::from_byte)
     }
 
+    /// Index of value in the constant table, adding it if an equal
+    /// constant isn't already there
     pub fn add_constant<V: Into<Value>>(&mut self, value: V) -> usize {
-        self.constants.push(value.into());
-        self.constants.len() - 1
+        let value = value.into();
+        let key = ConstantKey::new(&value);
+        if let Some(&idx) = key.and_then(|k| self.constant_idx.get(&k)) {
+            return idx;
+        }
+
+        self.constants.push(value);
+        let idx = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_idx.insert(key, idx);
+        }
+        idx
     }
 
     pub fn get_constant(&self, offset: usize) -> Option<&Value> {
         self.constants.get(offset)
     }
 
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    /// Operand bytes following the opcode at offset
+    pub fn operands(&self, offset: usize) -> &[u8] {
+        &self.code[offset + 1..offset + self.instruction_len(offset)]
+    }
+
+    /// Bytes taken by the instruction at offset including its operands
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        let op = self.get_op(offset).expect("an instruction");
+        let operands = match op {
+            OpCode::Closure | OpCode::ClosureLong => {
+                let constant_len = match OpLen::from(op) {
+                    OpLen::Short => 1,
+                    OpLen::Long => 2,
+                };
+                let count = self
+                    .get_byte(offset + 1 + constant_len)
+                    .expect("an upvalue count");
+                constant_len + 1 + 3 * count as usize
+            }
+            OpCode::InvokeLong | OpCode::SuperInvokeLong => 3,
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::Invoke
+            | OpCode::SuperInvoke
+            | OpCode::GetLocalAddConstant => 2,
+            OpCode::Constant
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Call
+            | OpCode::Class
+            | OpCode::BuildList
+            | OpCode::BuildMap
+            | OpCode::Method
+            | OpCode::SetLocalPop => 1,
+            _ => match OpLen::from(op) {
+                OpLen::Short => 0,
+                OpLen::Long => 2,
+            },
+        };
+
+        1 + operands
+    }
+
+    /// Offset a jump or loop instruction at offset goes to
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let distance = join_u8s(self.get_byte(offset + 1)?, self.get_byte(offset + 2)?) as usize;
+        match self.get_op(offset)? {
+            OpCode::Jump | OpCode::JumpIfFalse => Some(offset + 3 + distance),
+            OpCode::Loop => Some(offset + 3 - distance),
+            _ => None,
+        }
+    }
+
+    /// Discard code from offset onwards
+    pub fn truncate(&mut self, offset: usize) {
+        self.code.truncate(offset);
+        self.lines.retain(|l| l.offset < offset);
+        for local in &mut self.locals {
+            local.start = local.start.min(offset);
+            local.end = local.end.map(|end| end.min(offset));
+        }
+        self.statements.retain(|s| s.offset <= offset);
+    }
+
+    /// Discard constants from len onwards, which no remaining code may use
+    pub fn truncate_constants(&mut self, len: usize) {
+        for constant in self.constants.drain(len.min(self.constants.len())..) {
+            if let Some(key) = ConstantKey::new(&constant) {
+                self.constant_idx.remove(&key);
+            }
+        }
+    }
+
+    /// Replace the code and line info with those of other, keeping
+    /// the constants, local names and statements
+    pub fn replace_code(&mut self, other: Chunk) {
+        self.code = other.code;
+        self.lines = other.lines;
+    }
+
+    /// Record that slot holds the local name from the end of the code
+    /// written so far
+    pub fn begin_local(&mut self, name: &str, slot: usize) {
+        self.locals.push(LocalInfo {
+            name: name.to_owned(),
+            slot,
+            start: self.code.len(),
+            end: None,
+        })
+    }
+
+    /// Record that the local in slot goes out of scope at the end of
+    /// the code written so far
+    pub fn end_local(&mut self, slot: usize) {
+        let end = self.code.len();
+        if let Some(local) = self
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|l| l.slot == slot && l.end.is_none())
+        {
+            local.end = Some(end);
+        }
+    }
+
+    /// Every local recorded for the chunk, ordered by where they begin
+    pub fn locals(&self) -> &[LocalInfo] {
+        &self.locals
+    }
+
+    /// Record that a statement on line starts at the end of the code
+    /// written so far. Statements nested at the same offset, such as the
+    /// first in a block, replace the enclosing one
+    pub fn begin_statement(&mut self, line: usize) {
+        let offset = self.code.len();
+        match self.statements.last_mut() {
+            Some(last) if last.offset == offset => last.line = line,
+            _ => self.statements.push(LineStart { offset, line }),
+        }
+    }
+
+    /// Offsets where statements begin
+    pub fn statement_offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.statements.iter().map(|s| s.offset)
+    }
+
+    /// Line of the statement whose code begins at offset, None if offset
+    /// is inside a statement
+    pub fn statement_at(&self, offset: usize) -> Option<usize> {
+        let idx = self
+            .statements
+            .binary_search_by_key(&offset, |s| s.offset)
+            .ok()?;
+        Some(self.statements[idx].line)
+    }
+
+    /// Move locals and statements with the code, offsets maps old
+    /// instruction offsets to new ones
+    pub fn remap_debug_info(&mut self, offsets: &HashMap<usize, usize>) {
+        for local in &mut self.locals {
+            local.start = offsets[&local.start];
+            local.end = local.end.map(|end| offsets[&end]);
+        }
+        for statement in &mut self.statements {
+            statement.offset = offsets[&statement.offset];
+        }
+    }
+
+    /// Locals in scope when the instruction at offset runs. Where several
+    /// share a name only the innermost is visible, which also hides the
+    /// loop variable a for loop copies for each iteration
+    pub fn locals_at(&self, offset: usize) -> Vec<&LocalInfo> {
+        let live: Vec<_> = self
+            .locals
+            .iter()
+            .filter(|l| l.start <= offset && l.end.is_none_or(|end| offset < end))
+            .collect();
+
+        live.iter()
+            .enumerate()
+            .filter(|&(i, l)| live[i + 1..].iter().all(|inner| inner.name != l.name))
+            .map(|(_, &l)| l)
+            .collect()
+    }
+
     pub fn get_line(&self, instruction: usize) -> usize {
         let mut start = 0;
         let mut end = self.lines.len();
@@ -176,113 +462,288 @@ impl Chunk {
         self.code.len()
     }
 
-    pub fn disassemble_chunk(&self, name: &str) {
-        println!("== {name} ==");
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn disassemble_chunk(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {name} ==")?;
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, out)?;
         }
+
+        Ok(())
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{offset:04} ");
+    pub fn disassemble_instruction(&self, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        write!(out, "{offset:04} ")?;
 
         let line = self.get_line(offset);
         if offset > 0 && line == self.get_line(offset - 1) {
-            print!("\t| ")
+            write!(out, "\t| ")?
         } else {
-            print!("{:4} ", line)
+            write!(out, "{:4} ", line)?
         }
 
-        match self.code[offset] {
-            OpCode::Return => self.simple_instruction("RETURN", offset),
-            OpCode::Constant => self.constant_instruction("CONSTANT", offset),
-            OpCode::ConstantLong => self.constant_long_instruction("CONSTANT_LONG", offset),
-            OpCode::Nil => self.simple_instruction("NIL", offset),
-            OpCode::True => self.simple_instruction("TRUE", offset),
-            OpCode::False => self.simple_instruction("FALSE", offset),
-            OpCode::Pop => self.simple_instruction("POP", offset),
-            OpCode::GetLocal => self.byte_instruction("GET_LOCAL", offset),
-            OpCode::GetLocalLong => self.byte_long_instruction("GET_LOCAL_LONG", offset),
-            OpCode::SetLocal => self.byte_instruction("SET_LOCAL", offset),
-            OpCode::SetLocalLong => self.byte_long_instruction("SET_LOCAL_LONG", offset),
-            OpCode::GetGlobal => self.constant_instruction("GET_GLOBAL", offset),
-            OpCode::GetGlobalLong => self.constant_long_instruction("GET_GLOBAL_LONG", offset),
-            OpCode::DefineGlobal => self.constant_instruction("DEFINE_GLOBAL", offset),
+        let Some(op) = self.get_op(offset) else {
+            writeln!(out, "Unknown opcode {}", self.code[offset])?;
+            return Ok(offset + 1);
+        };
+
+        match op {
+            OpCode::Return => self.simple_instruction("RETURN", offset, out),
+            OpCode::Constant => self.constant_instruction("CONSTANT", offset, out),
+            OpCode::ConstantLong => self.constant_long_instruction("CONSTANT_LONG", offset, out),
+            OpCode::Nil => self.simple_instruction("NIL", offset, out),
+            OpCode::True => self.simple_instruction("TRUE", offset, out),
+            OpCode::False => self.simple_instruction("FALSE", offset, out),
+            OpCode::Pop => self.simple_instruction("POP", offset, out),
+            OpCode::GetLocal => self.byte_instruction("GET_LOCAL", offset, out),
+            OpCode::GetLocalLong => self.byte_long_instruction("GET_LOCAL_LONG", offset, out),
+            OpCode::SetLocal => self.byte_instruction("SET_LOCAL", offset, out),
+            OpCode::SetLocalLong => self.byte_long_instruction("SET_LOCAL_LONG", offset, out),
+            OpCode::GetGlobal => self.byte_instruction("GET_GLOBAL", offset, out),
+            OpCode::GetGlobalLong => self.byte_long_instruction("GET_GLOBAL_LONG", offset, out),
+            OpCode::DefineGlobal => self.byte_instruction("DEFINE_GLOBAL", offset, out),
             OpCode::DefineGlobalLong => {
-                self.constant_long_instruction("DEFINE_GLOBAL_LONG", offset)
+                self.byte_long_instruction("DEFINE_GLOBAL_LONG", offset, out)
             }
-            OpCode::SetGlobal => self.constant_instruction("SET_GLOBAL", offset),
-            OpCode::SetGlobalLong => self.constant_long_instruction("SET_GLOBAL_LONG", offset),
-            OpCode::Equal => self.simple_instruction("EQUAL", offset),
-            OpCode::Greater => self.simple_instruction("GREATER", offset),
-            OpCode::Less => self.simple_instruction("LESS", offset),
-            OpCode::Add => self.simple_instruction("ADD", offset),
-            OpCode::Subtract => self.simple_instruction("SUBTRACT", offset),
-            OpCode::Multiply => self.simple_instruction("MULTIPLY", offset),
-            OpCode::Divide => self.simple_instruction("DIVIDE", offset),
-            OpCode::Not => self.simple_instruction("NOT", offset),
-            OpCode::Negate => self.simple_instruction("NEGATE", offset),
-            OpCode::Print => self.simple_instruction("PRINT", offset),
-            OpCode::Jump => self.jump_instruction("JUMP", 1, offset),
-            OpCode::JumpIfFalse => self.jump_instruction("JUMP_IF_FALSE", 1, offset),
-            OpCode::Loop => self.jump_instruction("LOOP", -1, offset),
-            OpCode::Byte(b) => {
-                println!("Unknown opcode {b}");
-                offset + 1
+            OpCode::SetGlobal => self.byte_instruction("SET_GLOBAL", offset, out),
+            OpCode::SetGlobalLong => self.byte_long_instruction("SET_GLOBAL_LONG", offset, out),
+            OpCode::GetUpvalue => self.byte_instruction("GET_UPVALUE", offset, out),
+            OpCode::SetUpvalue => self.byte_instruction("SET_UPVALUE", offset, out),
+            OpCode::GetProperty => self.constant_instruction("GET_PROPERTY", offset, out),
+            OpCode::GetPropertyLong => {
+                self.constant_long_instruction("GET_PROPERTY_LONG", offset, out)
+            }
+            OpCode::SetProperty => self.constant_instruction("SET_PROPERTY", offset, out),
+            OpCode::SetPropertyLong => {
+                self.constant_long_instruction("SET_PROPERTY_LONG", offset, out)
+            }
+            OpCode::GetSuper => self.constant_instruction("GET_SUPER", offset, out),
+            OpCode::GetIndex => self.simple_instruction("GET_INDEX", offset, out),
+            OpCode::SetIndex => self.simple_instruction("SET_INDEX", offset, out),
+            OpCode::GetSuperLong => self.constant_long_instruction("GET_SUPER_LONG", offset, out),
+            OpCode::Equal => self.simple_instruction("EQUAL", offset, out),
+            OpCode::Greater => self.simple_instruction("GREATER", offset, out),
+            OpCode::Less => self.simple_instruction("LESS", offset, out),
+            OpCode::Add => self.simple_instruction("ADD", offset, out),
+            OpCode::Subtract => self.simple_instruction("SUBTRACT", offset, out),
+            OpCode::Multiply => self.simple_instruction("MULTIPLY", offset, out),
+            OpCode::Divide => self.simple_instruction("DIVIDE", offset, out),
+            OpCode::Not => self.simple_instruction("NOT", offset, out),
+            OpCode::Negate => self.simple_instruction("NEGATE", offset, out),
+            OpCode::Stringify => self.simple_instruction("STRINGIFY", offset, out),
+            OpCode::Print => self.simple_instruction("PRINT", offset, out),
+            OpCode::Jump => self.jump_instruction("JUMP", 1, offset, out),
+            OpCode::JumpIfFalse => self.jump_instruction("JUMP_IF_FALSE", 1, offset, out),
+            OpCode::Loop => self.jump_instruction("LOOP", -1, offset, out),
+            OpCode::Call => self.byte_instruction("CALL", offset, out),
+            OpCode::Invoke => self.invoke_instruction("INVOKE", OpLen::Short, offset, out),
+            OpCode::InvokeLong => self.invoke_instruction("INVOKE_LONG", OpLen::Long, offset, out),
+            OpCode::SuperInvoke => {
+                self.invoke_instruction("SUPER_INVOKE", OpLen::Short, offset, out)
+            }
+            OpCode::SuperInvokeLong => {
+                self.invoke_instruction("SUPER_INVOKE_LONG", OpLen::Long, offset, out)
+            }
+            OpCode::Closure => {
+                let offset = self.constant_instruction("CLOSURE", offset, out)?;
+                self.upvalues(offset, out)
+            }
+            OpCode::ClosureLong => {
+                let offset = self.constant_long_instruction("CLOSURE_LONG", offset, out)?;
+                self.upvalues(offset, out)
+            }
+            OpCode::CloseUpvalue => self.simple_instruction("CLOSE_UPVALUE", offset, out),
+            OpCode::Class => self.constant_instruction("CLASS", offset, out),
+            OpCode::ClassLong => self.constant_long_instruction("CLASS_LONG", offset, out),
+            OpCode::Inherit => self.simple_instruction("INHERIT", offset, out),
+            OpCode::BuildList => self.byte_instruction("BUILD_LIST", offset, out),
+            OpCode::BuildMap => self.byte_instruction("BUILD_MAP", offset, out),
+            OpCode::Method => self.constant_instruction("METHOD", offset, out),
+            OpCode::MethodLong => self.constant_long_instruction("METHOD_LONG", offset, out),
+            OpCode::NotEqual => self.simple_instruction("NOT_EQUAL", offset, out),
+            OpCode::GreaterEqual => self.simple_instruction("GREATER_EQUAL", offset, out),
+            OpCode::LessEqual => self.simple_instruction("LESS_EQUAL", offset, out),
+            OpCode::SetLocalPop => self.byte_instruction("SET_LOCAL_POP", offset, out),
+            OpCode::GetLocalAddConstant => {
+                let slot = self.get_byte(offset + 1).unwrap();
+                let constant = self.get_byte(offset + 2).unwrap();
+                let value = self.get_constant(constant as usize).unwrap();
+                writeln!(
+                    out,
+                    "{:<16} {slot:4} {constant:4} '{value}'",
+                    "GET_LOCAL_ADD_CONSTANT"
+                )?;
+                Ok(offset + 3)
             }
         }
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{name}");
-        offset + 1
+    fn simple_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        writeln!(out, "{name}")?;
+        Ok(offset + 1)
     }
 
-    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+    fn byte_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
         let slot = self.get_byte(offset + 1).unwrap();
-        println!("{name:<16} {slot:4}");
-        offset + 2
+        writeln!(out, "{name:<16} {slot:4}")?;
+        Ok(offset + 2)
     }
 
-    fn byte_long_instruction(&self, name: &str, offset: usize) -> usize {
+    fn byte_long_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
         let s1 = self.get_byte(offset + 1).unwrap();
         let s2 = self.get_byte(offset + 2).unwrap();
         let slot = join_u8s(s1, s2);
-        println!("{name:<16} {slot:4}");
-        offset + 3
+        writeln!(out, "{name:<16} {slot:4}")?;
+        Ok(offset + 3)
     }
 
-    fn jump_instruction(&self, name: &str, sign: isize, offset: usize) -> usize {
+    fn jump_instruction(
+        &self,
+        name: &str,
+        sign: isize,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
         let s1 = self.get_byte(offset + 1).unwrap();
         let s2 = self.get_byte(offset + 2).unwrap();
         let jump = join_u8s(s1, s2);
         let to: isize = (offset as isize) + 3 + (sign * jump as isize);
-        println!("{name:<16} {offset:4} -> {to}");
+        writeln!(out, "{name:<16} {offset:4} -> {to}")?;
 
-        offset + 3
+        Ok(offset + 3)
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+    /// Closure instructions are followed by upvalue count then an
+    /// is_local byte and two index bytes for each upvalue
+    fn upvalues(&self, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let count = self.get_byte(offset).unwrap() as usize;
+        let mut offset = offset + 1;
+
+        for _ in 0..count {
+            let is_local = self.get_byte(offset).unwrap();
+            let i1 = self.get_byte(offset + 1).unwrap();
+            let i2 = self.get_byte(offset + 2).unwrap();
+            let index = join_u8s(i1, i2);
+            let kind = if is_local == 1 { "local" } else { "upvalue" };
+            writeln!(out, "{offset:04}\t|\t\t\t{kind} {index}")?;
+            offset += 3;
+        }
+
+        Ok(offset)
+    }
+
+    /// Method name constant followed by argument count
+    fn invoke_instruction(
+        &self,
+        name: &str,
+        len: OpLen,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
+        let (constant, offset) = match len {
+            OpLen::Short => (self.get_byte(offset + 1).unwrap() as u16, offset + 2),
+            OpLen::Long => {
+                let c1 = self.get_byte(offset + 1).unwrap();
+                let c2 = self.get_byte(offset + 2).unwrap();
+                (join_u8s(c1, c2), offset + 3)
+            }
+        };
+        let arg_count = self.get_byte(offset).unwrap();
+
+        let value = self.get_constant(constant as usize).unwrap();
+        writeln!(out, "{name:<16} ({arg_count} args) {constant:4} '{value}'")?;
+
+        Ok(offset + 1)
+    }
+
+    fn constant_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
         let constant = self.get_byte(offset + 1).unwrap();
-        print!("{name:<16} {constant:4} ");
+        write!(out, "{name:<16} {constant:4} ")?;
 
         let value = self.get_constant(constant as usize).unwrap();
-        println!("'{value}'");
+        writeln!(out, "'{value}'")?;
 
-        offset + 2
+        Ok(offset + 2)
     }
 
-    fn constant_long_instruction(&self, name: &str, offset: usize) -> usize {
+    fn constant_long_instruction(
+        &self,
+        name: &str,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> io::Result<usize> {
         let c1 = self.get_byte(offset + 1).unwrap();
         let c2 = self.get_byte(offset + 2).unwrap();
         let constant = join_u8s(c1, c2);
-        print!("{name:<16} {constant:4} ");
+        write!(out, "{name:<16} {constant:4} ")?;
 
         let value = self.get_constant(constant as usize).unwrap();
-        println!("'{value}'");
+        writeln!(out, "'{value}'")?;
+
+        Ok(offset + 3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        for &op in OPCODES {
+            assert_eq!(OpCode::from_byte(op.into()), Some(op));
+        }
+        assert_eq!(OpCode::from_byte(OPCODES.len() as u8), None);
+
+        let mut chunk = Chunk::new();
+        chunk.write_maybe_long((OpCode::Constant, OpCode::ConstantLong), 300, 1);
+        assert_eq!(chunk.len(), 3);
+        assert_eq!(chunk.get_op(0), Some(OpCode::ConstantLong));
+        assert_eq!(
+            chunk.get_byte(1).zip(chunk.get_byte(2)),
+            Some(split_u16(300))
+        );
+    }
 
-        offset + 3
+    #[test]
+    fn test_constant_dedup() {
+        let mut chunk = Chunk::new();
+        let a = chunk.add_constant(1.5);
+        let b = chunk.add_constant(IString(3));
+        assert_eq!(chunk.add_constant(1.5), a);
+        assert_eq!(chunk.add_constant(IString(3)), b);
+
+        // Numbers need the same bits rather than be equal, and a string and
+        // object sharing a handle are different
+        assert_ne!(chunk.add_constant(-0.0), chunk.add_constant(0.0));
+        assert_ne!(chunk.add_constant(1.5 + 1e-12), a);
+        assert_ne!(chunk.add_constant(ObjRef(3)), b);
+        assert_eq!(chunk.add_constant(f64::NAN), chunk.add_constant(f64::NAN));
+        assert_eq!(chunk.constants().len(), 7);
     }
 }
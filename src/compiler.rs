@@ -1,10 +1,16 @@
+use std::collections::HashMap;
+#[cfg(feature = "debug_print_code")]
+use std::io::{self, Write};
+
 use crate::{
     chunk::{Chunk, OpCode},
-    object::StringInterner,
+    globals::Globals,
+    heap::Heap,
+    object::{Function, IString, Obj},
     scanner::{Scanner, Token, TokenType},
-    util::split_u16,
+    util::{join_u8s, split_u16},
     value::Value,
-    vm::{InterpretError, InterpretResult},
+    vm::{Diagnostic, ErrorKind, InterpretError, InterpretResult},
 };
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -55,7 +61,7 @@ enum ParseRule<'a, 'input, 'vm> {
 }
 
 impl<'a, 'input, 'vm> ParseRule<'a, 'input, 'vm> {
-    pub fn as_rule(self) -> Option<Rule<'a, 'input, 'vm>> {
+    pub fn into_rule(self) -> Option<Rule<'a, 'input, 'vm>> {
         if let ParseRule::Rule(rule) = self {
             rule
         } else {
@@ -63,7 +69,7 @@ impl<'a, 'input, 'vm> ParseRule<'a, 'input, 'vm> {
         }
     }
 
-    pub fn as_precedence(self) -> Precedence {
+    pub fn into_precedence(self) -> Precedence {
         if let ParseRule::Precedence(prec) = self {
             prec
         } else {
@@ -84,12 +90,23 @@ fn get_rule<'a, 'input, 'vm>(typ: TokenType, rule_type: RuleType) -> ParseRule<'
     }
 
     match typ {
-        TokenType::LParen => rule!(Some(Compiler::grouping), None, Precedence::None),
+        TokenType::LParen => rule!(
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call
+        ),
         TokenType::RParen => rule!(None, None, Precedence::None),
-        TokenType::LBrace => rule!(None, None, Precedence::None),
+        TokenType::LBrace => rule!(Some(Compiler::map), None, Precedence::None),
         TokenType::RBrace => rule!(None, None, Precedence::None),
+        TokenType::LBracket => rule!(
+            Some(Compiler::list),
+            Some(Compiler::index),
+            Precedence::Call
+        ),
+        TokenType::RBracket => rule!(None, None, Precedence::None),
+        TokenType::Colon => rule!(None, None, Precedence::None),
         TokenType::Comma => rule!(None, None, Precedence::None),
-        TokenType::Dot => rule!(None, None, Precedence::None),
+        TokenType::Dot => rule!(None, Some(Compiler::dot), Precedence::Call),
         TokenType::Minus => rule!(
             Some(Compiler::unary),
             Some(Compiler::binary),
@@ -109,20 +126,25 @@ fn get_rule<'a, 'input, 'vm>(typ: TokenType, rule_type: RuleType) -> ParseRule<'
         TokenType::LessEqual => rule!(None, Some(Compiler::binary), Precedence::Comparison),
         TokenType::Identifier => rule!(Some(Compiler::variable), None, Precedence::None),
         TokenType::String => rule!(Some(Compiler::string), None, Precedence::None),
+        TokenType::Interpolation => {
+            rule!(Some(Compiler::interpolation), None, Precedence::None)
+        }
         TokenType::Number => rule!(Some(Compiler::number), None, Precedence::None),
         TokenType::And => rule!(None, Some(Compiler::and), Precedence::And),
+        TokenType::Break => rule!(None, None, Precedence::None),
         TokenType::Class => rule!(None, None, Precedence::None),
+        TokenType::Continue => rule!(None, None, Precedence::None),
         TokenType::Else => rule!(None, None, Precedence::None),
         TokenType::False => rule!(Some(Compiler::literal), None, Precedence::None),
         TokenType::For => rule!(None, None, Precedence::None),
-        TokenType::Fun => rule!(None, None, Precedence::None),
+        TokenType::Fun => rule!(Some(Compiler::lambda), None, Precedence::None),
         TokenType::If => rule!(None, None, Precedence::None),
         TokenType::Nil => rule!(Some(Compiler::literal), None, Precedence::None),
         TokenType::Or => rule!(None, Some(Compiler::or), Precedence::Or),
         TokenType::Print => rule!(None, None, Precedence::None),
         TokenType::Return => rule!(None, None, Precedence::None),
-        TokenType::Super => rule!(None, None, Precedence::None),
-        TokenType::This => rule!(None, None, Precedence::None),
+        TokenType::Super => rule!(Some(Compiler::super_), None, Precedence::None),
+        TokenType::This => rule!(Some(Compiler::this), None, Precedence::None),
         TokenType::True => rule!(Some(Compiler::literal), None, Precedence::None),
         TokenType::Var => rule!(None, None, Precedence::None),
         TokenType::While => rule!(None, None, Precedence::None),
@@ -131,12 +153,29 @@ fn get_rule<'a, 'input, 'vm>(typ: TokenType, rule_type: RuleType) -> ParseRule<'
     }
 }
 
+/// Token for names the compiler declares itself
+fn synthetic_token(src: &'static str) -> Token<'static> {
+    let typ = match src {
+        "this" => TokenType::This,
+        "super" => TokenType::Super,
+        _ => TokenType::Identifier,
+    };
+
+    Token {
+        typ,
+        src,
+        line: 0,
+        column: 0,
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Parser<'input> {
     pub current: Token<'input>,
     pub previous: Token<'input>,
     pub had_error: bool,
     pub panic_mode: bool,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl<'input> Parser<'input> {
@@ -154,15 +193,19 @@ impl<'input> Parser<'input> {
         }
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
-
-        match token.typ {
-            TokenType::Eof => eprint!(" at end"),
-            TokenType::Error => {}
-            _ => eprint!(" at {}", token.src),
-        }
-
-        eprintln!(": {msg}");
+        // Error tokens hold the message instead of source text
+        let lexeme = match token.typ {
+            TokenType::Error => None,
+            _ => Some(token.src.to_owned()),
+        };
+        self.diagnostics.push(Diagnostic {
+            kind: ErrorKind::Compile,
+            message: msg.to_owned(),
+            line: token.line,
+            column: Some(token.column),
+            lexeme,
+            trace: Vec::new(),
+        });
         self.had_error = true
     }
 }
@@ -171,47 +214,203 @@ impl<'input> Parser<'input> {
 pub struct Local<'input> {
     name: Token<'input>,
     depth: Option<usize>,
+    is_captured: bool,
+}
+
+/// Where a closure finds a captured variable when it is created, either
+/// a local slot in the enclosing function or one of its upvalues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UpvalueDesc {
+    index: usize,
+    is_local: bool,
+}
+
+/// Loop whose body is being compiled. Break and continue jump forward
+/// and are patched once the loop's exit and continue points are known
+struct LoopState {
+    /// Locals deeper than this are discarded by 'break'
+    exit_depth: usize,
+    /// Locals deeper than this are discarded by 'continue'
+    body_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+impl LoopState {
+    fn new(exit_depth: usize, body_depth: usize) -> Self {
+        Self {
+            exit_depth,
+            body_depth,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// State for the function currently being compiled. Function declarations
+/// nest so the compiler keeps a stack of these
+struct FunctionState<'input> {
+    function: Function,
+    typ: FunctionType,
+    locals: Vec<Local<'input>>,
+    upvalues: Vec<UpvalueDesc>,
+    loops: Vec<LoopState>,
+    scope_depth: usize,
+}
+
+impl<'input> FunctionState<'input> {
+    fn new(mut function: Function, typ: FunctionType) -> Self {
+        let mut locals = Vec::with_capacity(u8::MAX as usize);
+
+        // Slot zero holds the function being called, or the receiver
+        // for methods so it can be accessed through 'this'
+        let name = match typ {
+            FunctionType::Initializer | FunctionType::Method => synthetic_token("this"),
+            FunctionType::Function | FunctionType::Script => Token::default(),
+        };
+        if !name.src.is_empty() {
+            function.chunk.begin_local(name.src, 0);
+        }
+        locals.push(Local {
+            name,
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        Self {
+            function,
+            typ,
+            locals,
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+/// Class declaration currently being compiled
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Point in the current chunk that code can be discarded back to, along
+/// with the constants added after it
+#[derive(Debug, Clone, Copy, Default)]
+struct Mark {
+    code: usize,
+    constants: usize,
 }
 
 pub struct Compiler<'input, 'vm> {
     scanner: Scanner<'input>,
     parser: Parser<'input>,
-    interner: &'vm mut StringInterner,
-    compiling_chunk: Chunk,
-    locals: Vec<Local<'input>>,
-    scope_depth: usize,
+    heap: &'vm mut Heap,
+    globals: &'vm mut Globals,
+    /// Slots given to globals this source names for the first time, only
+    /// added to globals once it compiles
+    new_globals: HashMap<String, usize>,
+    states: Vec<FunctionState<'input>>,
+    classes: Vec<ClassState>,
+    /// Where the left operand of the infix rule being compiled starts
+    operand_start: Mark,
+    /// Where compiled functions are disassembled, stdout when not set
+    #[cfg(feature = "debug_print_code")]
+    out: Option<&'vm mut dyn Write>,
 }
 
 impl<'input, 'vm> Compiler<'input, 'vm> {
-    pub fn new(src: &'input str, interner: &'vm mut StringInterner) -> Self {
+    pub fn new(src: &'input str, heap: &'vm mut Heap, globals: &'vm mut Globals) -> Self {
         Self {
             scanner: Scanner::new(src),
             parser: Parser::default(),
-            compiling_chunk: Chunk::new(),
-            locals: Vec::with_capacity(u8::MAX as usize),
-            scope_depth: 0,
-            interner,
+            states: vec![FunctionState::new(
+                Function::new(None),
+                FunctionType::Script,
+            )],
+            classes: Vec::new(),
+            operand_start: Mark::default(),
+            heap,
+            globals,
+            new_globals: HashMap::new(),
+            #[cfg(feature = "debug_print_code")]
+            out: None,
         }
     }
 
-    pub fn compile(mut self) -> InterpretResult<Chunk> {
+    #[cfg(feature = "debug_print_code")]
+    pub fn with_output(mut self, out: &'vm mut dyn Write) -> Self {
+        self.out = Some(out);
+        self
+    }
+
+    /// Compile the source into the function for the top level script
+    pub fn compile(mut self) -> InterpretResult<Function> {
         self.advance();
         while !self.matches(TokenType::Eof) {
             self.declaration()
         }
-        self.end_compiler();
+        let function = self.end_compiler();
 
         if self.parser.had_error {
-            Err(InterpretError::Compile)
-        } else {
-            Ok(self.compiling_chunk)
+            return Err(InterpretError::Compile(self.parser.diagnostics));
+        }
+
+        let mut new_globals: Vec<_> = self.new_globals.into_iter().collect();
+        new_globals.sort_unstable_by_key(|&(_, slot)| slot);
+        for (name, slot) in new_globals {
+            let resolved = self.globals.resolve(&name);
+            debug_assert_eq!(resolved, slot);
+        }
+        Ok(function)
+    }
+
+    fn current(&mut self) -> &mut FunctionState<'input> {
+        self.states.last_mut().expect("a function being compiled")
+    }
+
+    fn current_class(&mut self) -> &mut ClassState {
+        self.classes.last_mut().expect("a class being compiled")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    fn scope_depth(&self) -> usize {
+        self.states
+            .last()
+            .expect("a function being compiled")
+            .scope_depth
+    }
+
+    fn mark(&mut self) -> Mark {
+        let chunk = self.current_chunk();
+        Mark {
+            code: chunk.len(),
+            constants: chunk.constants().len(),
         }
     }
 
+    /// Discard the code and constants added since mark
+    fn rewind(&mut self, mark: Mark) {
+        let chunk = self.current_chunk();
+        chunk.truncate(mark.code);
+        chunk.truncate_constants(mark.constants);
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence) {
+        let start = self.mark();
         self.advance();
         let can_assign = precedence <= Precedence::Assignment;
-        match get_rule(self.parser.previous.typ, RuleType::Prefix).as_rule() {
+        match get_rule(self.parser.previous.typ, RuleType::Prefix).into_rule() {
             Some(rule) => rule(self, can_assign),
             None => {
                 self.parser.error("Expect expression.");
@@ -219,13 +418,15 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
             }
         };
 
-        while precedence <= get_rule(self.parser.current.typ, RuleType::Precedence).as_precedence()
+        while precedence
+            <= get_rule(self.parser.current.typ, RuleType::Precedence).into_precedence()
         {
             self.advance();
             let rule = get_rule(self.parser.previous.typ, RuleType::Infix)
-                .as_rule()
+                .into_rule()
                 .expect("an infix parse rule");
 
+            self.operand_start = start;
             rule(self, can_assign)
         }
 
@@ -235,7 +436,12 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
     }
 
     fn declaration(&mut self) {
-        if self.matches(TokenType::Var) {
+        self.begin_statement();
+        if self.matches(TokenType::Class) {
+            self.class_declaration()
+        } else if self.matches(TokenType::Fun) {
+            self.fun_declaration()
+        } else if self.matches(TokenType::Var) {
             self.var_declaration()
         } else {
             self.statement();
@@ -246,6 +452,122 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         }
     }
 
+    fn class_declaration(&mut self) {
+        let global = self.parse_variable("Expect class name.");
+        let class_name = self.parser.previous;
+        let name_constant = self.identifier_constant(class_name.src);
+
+        self.emit_long((OpCode::Class, OpCode::ClassLong), name_constant);
+        self.define_variable(global);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.matches(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.src == self.parser.previous.src {
+                self.parser.error("A class can't inherit from itself.");
+            }
+
+            // Superclass lives in a local named super so methods can
+            // capture it, one scope per class avoids collisions
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name.src, false);
+            self.emit_byte(OpCode::Inherit);
+            self.current_class().has_superclass = true;
+        }
+
+        // Load class so methods can be attached to it
+        self.named_variable(class_name.src, false);
+        self.consume(TokenType::LBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop);
+
+        if self.current_class().has_superclass {
+            self.end_scope();
+        }
+
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.parser.previous.src;
+        let constant = self.identifier_constant(name);
+
+        let typ = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        let name = self.heap.intern(name);
+        self.function(typ, Some(name));
+        self.emit_long((OpCode::Method, OpCode::MethodLong), constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself in its body
+        self.mark_initialized();
+
+        let name = self.heap.intern(self.parser.previous.src);
+        self.function(FunctionType::Function, Some(name));
+        self.define_variable(global);
+    }
+
+    /// Compile parameters and body of a function into a new function object
+    /// leaving a closure over it on the stack
+    fn function(&mut self, typ: FunctionType, name: Option<IString>) {
+        self.states
+            .push(FunctionState::new(Function::new(name), typ));
+        self.begin_scope();
+
+        self.consume(TokenType::LParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RParen) {
+            loop {
+                self.current().function.arity += 1;
+                if self.current().function.arity > u8::MAX as usize {
+                    self.parser
+                        .error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No end_scope, the frame's slots are discarded on return
+        let upvalues = self.current().upvalues.clone();
+        let function = self.end_compiler();
+        let function = self.heap.alloc(Obj::Function(function));
+
+        let constant = self.make_constant(Value::from(function));
+        self.emit_long((OpCode::Closure, OpCode::ClosureLong), constant);
+        self.emit_byte(upvalues.len() as u8);
+        for upvalue in upvalues {
+            let (i1, i2) = split_u16(upvalue.index as u16);
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(i1);
+            self.emit_byte(i2);
+        }
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
@@ -261,23 +583,23 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         self.define_variable(global);
     }
 
-    /// Consume identifier token and add its lexeme to chunk's
-    /// constant table if in global scope returning its index
+    /// Consume identifier token and declare it, returning its global
+    /// slot if in global scope
     fn parse_variable(&mut self, msg: &str) -> usize {
         self.consume(TokenType::Identifier, msg);
 
         self.declare_variable();
-        if self.scope_depth > 0 {
+        if self.scope_depth() > 0 {
             return 0;
         }
 
-        self.identifier_constant(&self.parser.previous.src)
+        self.global_slot(self.parser.previous.src)
     }
 
     /// Variable is now ready for use
     fn define_variable(&mut self, global: usize) {
         // locals behave like stack
-        if self.scope_depth == 0 {
+        if self.scope_depth() == 0 {
             self.emit_long((OpCode::DefineGlobal, OpCode::DefineGlobalLong), global)
         } else {
             // Variable initializer is complete
@@ -285,35 +607,64 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         }
     }
 
-    /// Mark last local as initialized by setting current depth. Panics if no locals
+    /// Mark last local as initialized by setting current depth. Globals
+    /// have no local to mark
     fn mark_initialized(&mut self) {
-        let last_local = self.locals.last_mut().expect("At least one local");
-        last_local.depth = Some(self.scope_depth)
+        let state = self.current();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let slot = state.locals.len() - 1;
+        let last_local = &mut state.locals[slot];
+        last_local.depth = Some(state.scope_depth);
+        state.function.chunk.begin_local(last_local.name.src, slot)
+    }
+
+    /// Globals are referred to by slot index which is limited to u16. New
+    /// names get the slots following the existing globals
+    fn global_slot(&mut self, name: &str) -> usize {
+        let slot = match self.globals.slot(name) {
+            Some(slot) => slot,
+            None => {
+                let next = self.globals.len() + self.new_globals.len();
+                *self.new_globals.entry(name.to_owned()).or_insert(next)
+            }
+        };
+        if slot > u16::MAX as usize {
+            self.parser.error("Too many global variables.");
+            0
+        } else {
+            slot
+        }
     }
 
     /// Intern string and insert into constant table
     fn identifier_constant(&mut self, token: &str) -> usize {
-        let istr = self.interner.intern(token);
-        self.make_constant(Value::String(istr))
+        let istr = self.heap.intern(token);
+        self.make_constant(Value::from(istr))
     }
 
     /// Add local variable to locals. Variable is added to scope
     fn declare_variable(&mut self) {
-        if self.scope_depth == 0 {
+        let scope_depth = self.scope_depth();
+        if scope_depth == 0 {
             return;
         }
 
         let name = self.parser.previous;
-        for local in self.locals.iter().rev() {
+        let state = self.states.last().expect("a function being compiled");
+        for local in state.locals.iter().rev() {
             if let Some(depth) = local.depth {
-                if depth < self.scope_depth {
+                if depth < scope_depth {
                     break;
                 }
             }
 
             if name.src == local.name.src {
                 self.parser
-                    .error("Already a variable with this name in this scope.")
+                    .error("Already a variable with this name in this scope.");
+                break;
             }
         }
 
@@ -322,24 +673,43 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
 
     /// Locals refer to variables by slot index which is limited to u16
     fn add_local(&mut self, name: Token<'input>) {
-        if self.locals.len() > u16::MAX as usize {
+        if self.current().locals.len() > u16::MAX as usize {
             self.parser
                 .error("Too many local variables in one function.");
         } else {
-            self.locals.push(Local { name, depth: None })
+            self.current().locals.push(Local {
+                name,
+                depth: None,
+                is_captured: false,
+            })
         }
     }
 
+    /// Record where the statement about to be compiled begins so a
+    /// debugger can stop there
+    fn begin_statement(&mut self) {
+        let line = self.parser.current.line;
+        self.current_chunk().begin_statement(line)
+    }
+
     fn statement(&mut self) {
+        self.begin_statement();
         if self.matches(TokenType::Print) {
             self.print_statement()
         } else if self.matches(TokenType::For) {
             self.for_statement()
         } else if self.matches(TokenType::If) {
             self.if_statement()
+        } else if self.matches(TokenType::Return) {
+            self.return_statement()
         } else if self.matches(TokenType::While) {
             self.while_statement()
-        } else if self.matches(TokenType::LBrace) {
+        } else if self.matches(TokenType::Break) {
+            self.break_statement()
+        } else if self.matches(TokenType::Continue) {
+            self.continue_statement()
+        } else if self.check(TokenType::LBrace) && !self.starts_map() {
+            self.advance();
             self.begin_scope();
             self.block();
             self.end_scope()
@@ -348,42 +718,110 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         }
     }
 
+    /// A '{' starting a statement opens a map literal rather than a block
+    /// when its first entry is a single token key followed by ':'.
+    /// Other keys need the map to be wrapped in parentheses
+    fn starts_map(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        scanner.scan_token();
+        scanner.scan_token().typ == TokenType::Colon
+    }
+
     fn print_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
         self.emit_byte(OpCode::Print)
     }
 
+    fn return_statement(&mut self) {
+        if self.current().typ == FunctionType::Script {
+            self.parser.error("Can't return from top-level code.");
+        }
+
+        if self.matches(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().typ == FunctionType::Initializer {
+                self.parser
+                    .error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return);
+        }
+    }
+
     fn while_statement(&mut self) {
-        let loop_start = self.compiling_chunk.len();
+        let loop_start = self.current_chunk().len();
         self.consume(TokenType::LParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RParen, "Expect ')' after condition.");
 
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
         self.emit_byte(OpCode::Pop); // Cond true pop value
+
+        let depth = self.scope_depth();
+        self.current().loops.push(LoopState::new(depth, depth));
         self.statement();
+        let loop_state = self.current().loops.pop().expect("a loop");
+
+        self.patch_jumps(&loop_state.continues);
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::Pop); // Cond was false pop value
+        self.patch_jumps(&loop_state.breaks);
+    }
+
+    fn break_statement(&mut self) {
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+        let Some(depth) = self.current().loops.last().map(|l| l.exit_depth) else {
+            self.parser.error("Can't use 'break' outside of a loop.");
+            return;
+        };
+
+        self.discard_locals(depth);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.current().loops.last_mut().unwrap().breaks.push(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+        let Some(depth) = self.current().loops.last().map(|l| l.body_depth) else {
+            self.parser.error("Can't use 'continue' outside of a loop.");
+            return;
+        };
+
+        self.discard_locals(depth);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.current()
+            .loops
+            .last_mut()
+            .unwrap()
+            .continues
+            .push(jump);
     }
 
     fn for_statement(&mut self) {
         self.begin_scope(); // init variable should be scoped to for
-                            
+
         self.consume(TokenType::LParen, "Expect '(' after 'for'.");
 
         // Initializer clause
+        let mut loop_var = None;
         if self.matches(TokenType::Semicolon) {
             // No initializer
         } else if self.matches(TokenType::Var) {
             self.var_declaration();
+            let state = self.current();
+            let slot = state.locals.len() - 1;
+            loop_var = Some((slot, state.locals[slot].name));
         } else {
             self.expression_statement();
         }
 
-        let mut loop_start = self.compiling_chunk.len();
+        let mut loop_start = self.current_chunk().len();
         let mut exit_jump: Option<usize> = None;
 
         // Condition clause
@@ -400,7 +838,7 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         if !self.matches(TokenType::RParen) {
             // jump over body on first iter
             let body_jump = self.emit_jump(OpCode::Jump);
-            let increment_start = self.compiling_chunk.len();
+            let increment_start = self.current_chunk().len();
             self.expression();
             self.emit_byte(OpCode::Pop); // Discard increment expr
             self.consume(TokenType::RParen, "Expect ')' after for clauses.");
@@ -410,7 +848,32 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
             self.patch_jump(body_jump);
         }
 
+        // Each iteration gets a fresh copy of the loop variable so closures
+        // created in the body capture that iteration's value
+        let exit_depth = self.scope_depth();
+        let inner_var = loop_var.map(|(outer_slot, name)| {
+            self.begin_scope();
+            self.emit_long((OpCode::GetLocal, OpCode::GetLocalLong), outer_slot);
+            self.add_local(name);
+            self.mark_initialized();
+            (outer_slot, self.current().locals.len() - 1)
+        });
+
+        let body_depth = self.scope_depth();
+        self.current()
+            .loops
+            .push(LoopState::new(exit_depth, body_depth));
         self.statement();
+        let loop_state = self.current().loops.pop().expect("a loop");
+        self.patch_jumps(&loop_state.continues);
+
+        // Copy the value back so the increment clause sees body changes
+        if let Some((outer_slot, inner_slot)) = inner_var {
+            self.emit_long((OpCode::GetLocal, OpCode::GetLocalLong), inner_slot);
+            self.emit_long((OpCode::SetLocal, OpCode::SetLocalLong), outer_slot);
+            self.emit_byte(OpCode::Pop);
+            self.end_scope();
+        }
         self.emit_loop(loop_start);
 
         // Only patch jump when there is condition clause
@@ -418,6 +881,7 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
             self.patch_jump(exit_jump);
             self.emit_byte(OpCode::Pop); // cond if false
         }
+        self.patch_jumps(&loop_state.breaks);
 
         self.end_scope();
     }
@@ -483,13 +947,51 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
 
     fn number(&mut self, _can_assign: bool) {
         let value: f64 = self.parser.previous.src.parse().expect("a number");
-        self.emit_constant(Value::Num(value))
+        self.emit_constant(Value::from(value))
     }
 
     fn string(&mut self, _can_assign: bool) {
         let str = self.parser.previous.src;
-        let istr = self.interner.intern(&str[1..str.len() - 1]);
-        self.emit_constant(Value::String(istr))
+        let istr = self.heap.intern(&str[1..str.len() - 1]);
+        self.emit_constant(Value::from(istr))
+    }
+
+    /// Compiles "a${x}b${y}c" as "a" + str(x) + "b" + str(y) + "c". Each
+    /// Interpolation token holds the text before a '${' and the String
+    /// token after the last expression holds the rest
+    fn interpolation(&mut self, _can_assign: bool) {
+        let mut first = true;
+        loop {
+            let str = self.parser.previous.src;
+            let istr = self.heap.intern(&str[1..str.len() - 2]);
+            self.emit_constant(Value::from(istr));
+            if !first {
+                self.emit_byte(OpCode::Add);
+            }
+            first = false;
+
+            self.expression();
+            self.emit_bytes(OpCode::Stringify, OpCode::Add);
+
+            if !self.matches(TokenType::Interpolation) {
+                break;
+            }
+        }
+
+        self.consume(
+            TokenType::String,
+            "Expect '}' after interpolated expression.",
+        );
+        let str = self.parser.previous.src;
+        if str.len() > 2 {
+            let istr = self.heap.intern(&str[1..str.len() - 1]);
+            self.emit_constant(Value::from(istr));
+            self.emit_byte(OpCode::Add);
+        }
+    }
+
+    fn lambda(&mut self, _can_assign: bool) {
+        self.function(FunctionType::Function, None)
     }
 
     fn variable(&mut self, can_assign: bool) {
@@ -497,14 +999,22 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
     }
 
     fn named_variable(&mut self, token: &str, can_assign: bool) {
-        let (arg, get_ops, set_ops) = if let Some(arg) = self.resolve_local(token) {
+        let current = self.states.len() - 1;
+        let (arg, get_ops, set_ops) = if let Some(arg) = self.resolve_local(current, token) {
             (
                 arg,
                 (OpCode::GetLocal, OpCode::GetLocalLong),
                 (OpCode::SetLocal, OpCode::SetLocalLong),
             )
+        } else if let Some(arg) = self.resolve_upvalue(current, token) {
+            // Upvalue indices always fit in a byte so both ops are the same
+            (
+                arg,
+                (OpCode::GetUpvalue, OpCode::GetUpvalue),
+                (OpCode::SetUpvalue, OpCode::SetUpvalue),
+            )
         } else {
-            let arg = self.identifier_constant(token);
+            let arg = self.global_slot(token);
             (
                 arg,
                 (OpCode::GetGlobal, OpCode::GetGlobalLong),
@@ -520,18 +1030,201 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         }
     }
 
-    fn resolve_local(&mut self, name: &str) -> Option<usize> {
-        for (idx, local) in self.locals.iter().enumerate().rev() {
-            if local.name.src == name {
-                if local.depth.is_none() {
+    /// Find slot of local in the function at state index
+    fn resolve_local(&mut self, state: usize, name: &str) -> Option<usize> {
+        let (idx, local) = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.src == name)?;
+
+        if local.depth.is_none() {
+            self.parser
+                .error("Can't read local variable in its own initializer.")
+        }
+
+        Some(idx)
+    }
+
+    /// Find variable in enclosing functions, adding an upvalue to each
+    /// function between the one that declared it and the one at state index
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<usize> {
+        let enclosing = state.checked_sub(1)?;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.states[enclosing].locals[local].is_captured = true;
+            return Some(self.add_upvalue(state, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(state, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: usize, is_local: bool) -> usize {
+        let upvalue = UpvalueDesc { index, is_local };
+        let upvalues = &mut self.states[state].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
+        }
+
+        // The count is emitted as a byte so 255 is the most that fit
+        if upvalues.len() >= u8::MAX as usize {
+            self.parser.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        upvalues.push(upvalue);
+        self.states[state].function.upvalue_count += 1;
+        self.states[state].upvalues.len() - 1
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::Call, arg_count)
+    }
+
+    /// List literal, elements are left on the stack and gathered up by
+    /// the BuildList instruction
+    fn list(&mut self, _can_assign: bool) {
+        let mut item_count: usize = 0;
+        if !self.check(TokenType::RBracket) {
+            loop {
+                // Allow a trailing comma
+                if self.check(TokenType::RBracket) {
+                    break;
+                }
+
+                self.expression();
+                if item_count == u8::MAX as usize {
                     self.parser
-                        .error("Can't read local variable in its own initializer.")
+                        .error("Can't have more than 255 items in a list literal.");
+                }
+                item_count += 1;
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RBracket, "Expect ']' after list items.");
+        self.emit_bytes(OpCode::BuildList, item_count as u8)
+    }
+
+    /// Map literal, keys and values are left on the stack in pairs and
+    /// gathered up by the BuildMap instruction
+    fn map(&mut self, _can_assign: bool) {
+        let mut entry_count: usize = 0;
+        if !self.check(TokenType::RBrace) {
+            loop {
+                // Allow a trailing comma
+                if self.check(TokenType::RBrace) {
+                    break;
+                }
+
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == u8::MAX as usize {
+                    self.parser
+                        .error("Can't have more than 255 entries in a map literal.");
+                }
+                entry_count += 1;
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RBrace, "Expect '}' after map entries.");
+        self.emit_bytes(OpCode::BuildMap, entry_count as u8)
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RBracket, "Expect ']' after index.");
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::SetIndex);
+        } else {
+            self.emit_byte(OpCode::GetIndex);
+        }
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.parser.previous.src);
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_long((OpCode::SetProperty, OpCode::SetPropertyLong), name);
+        } else if self.matches(TokenType::LParen) {
+            // Call method directly instead of creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_long((OpCode::Invoke, OpCode::InvokeLong), name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_long((OpCode::GetProperty, OpCode::GetPropertyLong), name);
+        }
+    }
+
+    /// Superclass methods are resolved statically through the hidden
+    /// super local rather than looked up on the receiver
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.parser.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => self
+                .parser
+                .error("Can't use 'super' in a class with no superclass."),
+            _ => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.parser.previous.src);
+
+        self.named_variable("this", false);
+        if self.matches(TokenType::LParen) {
+            let arg_count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_long((OpCode::SuperInvoke, OpCode::SuperInvokeLong), name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable("super", false);
+            self.emit_long((OpCode::GetSuper, OpCode::GetSuperLong), name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.parser.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false)
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.parser.error("Can't have more than 255 arguments.");
                 }
+                arg_count += 1;
 
-                return Some(idx);
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
             }
         }
-        None
+
+        self.consume(TokenType::RParen, "Expect ')' after arguments.");
+        arg_count as u8
     }
 
     fn grouping(&mut self, _can_assign: bool) {
@@ -541,32 +1234,137 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
 
     fn unary(&mut self, _can_assign: bool) {
         let typ = self.parser.previous.typ;
+        let operand_start = self.mark();
         self.parse_precedence(Precedence::Unary);
 
-        match typ {
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
-            TokenType::Bang => self.emit_byte(OpCode::Not),
+        let op = match typ {
+            TokenType::Minus => OpCode::Negate,
+            TokenType::Bang => OpCode::Not,
             _ => unreachable!(),
+        };
+
+        let end = self.current_chunk().len();
+        let folded = self
+            .literal_value(operand_start.code, end)
+            .and_then(|a| self.fold_unary(op, a));
+        match folded {
+            Some(value) => {
+                self.rewind(operand_start);
+                self.emit_value(value)
+            }
+            None => self.emit_byte(op),
         }
     }
 
     fn binary(&mut self, _can_assign: bool) {
         let typ = self.parser.previous.typ;
-        let precedence = get_rule(typ, RuleType::Precedence).as_precedence();
+        let left_start = self.operand_start;
+        let right_start = self.current_chunk().len();
+        let precedence = get_rule(typ, RuleType::Precedence).into_precedence();
         self.parse_precedence(precedence.next());
 
-        match typ {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
+        // '!=', '>=' and '<=' negate another comparison
+        let (op, negate) = match typ {
+            TokenType::BangEqual => (OpCode::Equal, true),
+            TokenType::EqualEqual => (OpCode::Equal, false),
+            TokenType::Greater => (OpCode::Greater, false),
+            TokenType::GreaterEqual => (OpCode::Less, true),
+            TokenType::Less => (OpCode::Less, false),
+            TokenType::LessEqual => (OpCode::Greater, true),
+            TokenType::Plus => (OpCode::Add, false),
+            TokenType::Minus => (OpCode::Subtract, false),
+            TokenType::Star => (OpCode::Multiply, false),
+            TokenType::Slash => (OpCode::Divide, false),
             _ => unreachable!(),
+        };
+
+        let end = self.current_chunk().len();
+        let left = self.literal_value(left_start.code, right_start);
+        let right = self.literal_value(right_start, end);
+        let folded = match (left, right) {
+            (Some(a), Some(b)) => self.fold_binary(op, a, b),
+            _ => None,
+        };
+        let folded = match folded {
+            Some(value) if negate => self.fold_unary(OpCode::Not, value),
+            folded => folded,
+        };
+
+        match folded {
+            Some(value) => {
+                self.rewind(left_start);
+                self.emit_value(value)
+            }
+            None if negate => self.emit_bytes(op, OpCode::Not),
+            None => self.emit_byte(op),
+        }
+    }
+
+    /// Value of the literal compiled into the code from start to end, if
+    /// that code is exactly one instruction pushing a literal
+    fn literal_value(&mut self, start: usize, end: usize) -> Option<Value> {
+        let chunk = self.current_chunk();
+        if start >= end || start + chunk.instruction_len(start) != end {
+            return None;
+        }
+
+        match chunk.get_op(start)? {
+            OpCode::Nil => Some(Value::NIL),
+            OpCode::True => Some(Value::from(true)),
+            OpCode::False => Some(Value::from(false)),
+            OpCode::Constant => chunk
+                .get_constant(chunk.get_byte(start + 1)? as usize)
+                .copied(),
+            OpCode::ConstantLong => {
+                let idx = join_u8s(chunk.get_byte(start + 1)?, chunk.get_byte(start + 2)?);
+                chunk.get_constant(idx as usize).copied()
+            }
+            _ => None,
+        }
+    }
+
+    /// Result of op on a literal operand, or None when the VM would report
+    /// an error so it still does at runtime
+    fn fold_unary(&self, op: OpCode, a: Value) -> Option<Value> {
+        match op {
+            OpCode::Negate => a.as_num().map(|n| Value::from(-n)),
+            OpCode::Not => Some(Value::from(a.is_falsey())),
+            _ => None,
+        }
+    }
+
+    /// Result of op on literal operands, matching the VM's arithmetic and
+    /// comparisons. None when the VM would report an error
+    fn fold_binary(&mut self, op: OpCode, a: Value, b: Value) -> Option<Value> {
+        if op == OpCode::Equal {
+            return Some(Value::from(a.eq(&b)));
+        }
+
+        if let (OpCode::Add, Some(a), Some(b)) = (op, a.as_str(), b.as_str()) {
+            let concated = format!("{}{}", self.heap.get_str(a), self.heap.get_str(b));
+            return Some(Value::from(self.heap.intern(concated)));
+        }
+
+        let (a, b) = (a.as_num()?, b.as_num()?);
+        let value = match op {
+            OpCode::Greater => Value::from(a > b),
+            OpCode::Less => Value::from(a < b),
+            OpCode::Add => Value::from(a + b),
+            OpCode::Subtract => Value::from(a - b),
+            OpCode::Multiply => Value::from(a * b),
+            OpCode::Divide => Value::from(a / b),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Emit the shortest instruction pushing value
+    fn emit_value(&mut self, value: Value) {
+        match value.as_bool() {
+            Some(true) => self.emit_byte(OpCode::True),
+            Some(false) => self.emit_byte(OpCode::False),
+            None if value.is_nil() => self.emit_byte(OpCode::Nil),
+            None => self.emit_constant(value),
         }
     }
 
@@ -603,41 +1401,80 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         }
     }
 
-    fn end_compiler(&mut self) {
+    /// Finish the function currently being compiled and return it
+    fn end_compiler(&mut self) -> Function {
         self.emit_return();
+        let mut state = self.states.pop().expect("a function being compiled");
+        for slot in 0..state.locals.len() {
+            state.function.chunk.end_local(slot);
+        }
 
         #[cfg(feature = "debug_print_code")]
         if !self.parser.had_error {
-            self.compiling_chunk.disassemble_chunk("code");
+            let name = state
+                .function
+                .name
+                .map(|n| self.heap.get_str(n))
+                .unwrap_or("<script>");
+            let chunk = &state.function.chunk;
+            // Disassembly is best effort, failing to write it is not a
+            // compile error
+            let _ = match self.out.as_deref_mut() {
+                Some(out) => chunk.disassemble_chunk(name, out),
+                None => chunk.disassemble_chunk(name, &mut io::stdout()),
+            };
         }
+
+        state.function
     }
 
     fn begin_scope(&mut self) {
-        self.scope_depth += 1
+        self.current().scope_depth += 1
     }
 
     /// Look for variables at scope just left and discard. At runtime
-    /// locals occupy slot on stack so when they go out of scope, must pop
+    /// locals occupy slot on stack so when they go out of scope, must pop.
+    /// Captured locals are moved off the stack into their upvalue instead
     fn end_scope(&mut self) {
-        self.scope_depth -= 1;
+        self.current().scope_depth -= 1;
 
-        while self
+        let count = self.discard_locals(self.scope_depth());
+        let state = self.current();
+        let len = state.locals.len();
+        for slot in len - count..len {
+            state.function.chunk.end_local(slot);
+        }
+        state.locals.truncate(len - count);
+    }
+
+    /// Emit code removing locals deeper than depth from the stack, without
+    /// forgetting them at compile time. Returns how many were discarded
+    fn discard_locals(&mut self, depth: usize) -> usize {
+        let captured: Vec<_> = self
+            .current()
             .locals
-            .last()
-            .map(|l| l.depth.expect("initialized local") > self.scope_depth)
-            .unwrap_or(false)
-        {
-            self.emit_byte(OpCode::Pop);
-            self.locals.pop();
+            .iter()
+            .rev()
+            .take_while(|l| l.depth.expect("initialized local") > depth)
+            .map(|l| l.is_captured)
+            .collect();
+
+        for &is_captured in &captured {
+            if is_captured {
+                self.emit_byte(OpCode::CloseUpvalue);
+            } else {
+                self.emit_byte(OpCode::Pop);
+            }
         }
+        captured.len()
     }
 
-    fn emit_byte<B: Into<OpCode>>(&mut self, byte: B) {
-        self.compiling_chunk
-            .write_chunk(byte, self.parser.previous.line)
+    fn emit_byte<B: Into<u8>>(&mut self, byte: B) {
+        let line = self.parser.previous.line;
+        self.current_chunk().write_chunk(byte, line)
     }
 
-    fn emit_bytes(&mut self, b1: OpCode, b2: OpCode) {
+    fn emit_bytes<B1: Into<u8>, B2: Into<u8>>(&mut self, b1: B1, b2: B2) {
         self.emit_byte(b1);
         self.emit_byte(b2)
     }
@@ -645,7 +1482,7 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
     fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(OpCode::Loop);
 
-        let offset = self.compiling_chunk.len() - loop_start + 2;
+        let offset = self.current_chunk().len() - loop_start + 2;
         if offset > u16::MAX as usize {
             self.parser.error("Loop body too large.")
         }
@@ -660,11 +1497,11 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         self.emit_byte(u8::MAX);
         self.emit_byte(u8::MAX);
 
-        self.compiling_chunk.len() - 2
+        self.current_chunk().len() - 2
     }
 
     fn patch_jump(&mut self, offset: usize) {
-        let jump = self.compiling_chunk.len() - offset - 2;
+        let jump = self.current_chunk().len() - offset - 2;
         if jump > u16::MAX as usize {
             self.parser.error("Too much code to jump over.")
         }
@@ -672,35 +1509,37 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         let (j1, j2) = split_u16(jump as u16);
 
         let old_j1 = self
-            .compiling_chunk
+            .current_chunk()
             .get_byte_mut(offset)
             .expect("jump byte");
         *old_j1 = j1;
 
         let old_j2 = self
-            .compiling_chunk
+            .current_chunk()
             .get_byte_mut(offset + 1)
             .expect("jump byte");
         *old_j2 = j2;
     }
 
+    fn patch_jumps(&mut self, offsets: &[usize]) {
+        for &offset in offsets {
+            self.patch_jump(offset);
+        }
+    }
+
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.compiling_chunk.write_maybe_long(
-            (OpCode::Constant, OpCode::ConstantLong),
-            constant,
-            self.parser.previous.line,
-        );
+        self.emit_long((OpCode::Constant, OpCode::ConstantLong), constant)
     }
 
     fn emit_long(&mut self, pair: (OpCode, OpCode), byte: usize) {
-        self.compiling_chunk
-            .write_maybe_long(pair, byte, self.parser.previous.line);
+        let line = self.parser.previous.line;
+        self.current_chunk().write_maybe_long(pair, byte, line);
     }
 
     /// Insert constant into chunk, erroring if too many in table
     fn make_constant(&mut self, value: Value) -> usize {
-        let constant = self.compiling_chunk.add_constant(value);
+        let constant = self.current_chunk().add_constant(value);
         if constant > u16::MAX as usize {
             self.parser.error("Too many constants in one chunk.");
             0
@@ -709,7 +1548,15 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         }
     }
 
+    /// Functions without an explicit return implicitly return nil,
+    /// initializers always return the instance in slot zero
     fn emit_return(&mut self) {
+        if self.current().typ == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal, 0u8);
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+
         self.emit_byte(OpCode::Return)
     }
 
@@ -747,3 +1594,166 @@ impl<'input, 'vm> Compiler<'input, 'vm> {
         self.parser.current.typ == typ
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ops(src: &str) -> Vec<OpCode> {
+        let (mut heap, mut globals) = (Heap::new(), Globals::new());
+        let function = Compiler::new(src, &mut heap, &mut globals)
+            .compile()
+            .expect("source to compile");
+
+        let chunk = &function.chunk;
+        let mut ops = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.len() {
+            ops.push(chunk.get_op(offset).unwrap());
+            offset += chunk.instruction_len(offset);
+        }
+        ops
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        use OpCode::*;
+
+        assert_eq!(ops("print 60 * 60 * 24;"), [Constant, Print, Nil, Return]);
+        assert_eq!(ops("print \"a\" + \"b\";"), [Constant, Print, Nil, Return]);
+        assert_eq!(ops("print -(1 + 2) >= 3;"), [False, Print, Nil, Return]);
+        assert_eq!(ops("print !nil == (1 != 2);"), [True, Print, Nil, Return]);
+        assert_eq!(ops("print 1 / 0;"), [Constant, Print, Nil, Return]);
+
+        // Only the folded result is left in the constant table, while a
+        // constant used before folding is kept
+        let constants = |src: &str| {
+            let (mut heap, mut globals) = (Heap::new(), Globals::new());
+            let function = Compiler::new(src, &mut heap, &mut globals)
+                .compile()
+                .expect("source to compile");
+            function.chunk.constants().len()
+        };
+        assert_eq!(constants("print 60 * 60 * 24;"), 1);
+        assert_eq!(constants("print \"a\" + \"b\" + \"c\";"), 1);
+        assert_eq!(constants("print 2; print 2 * 3;"), 2);
+
+        // Operands that aren't both literals are left alone
+        assert_eq!(
+            ops("var a; print a + 1 + 2;"),
+            [
+                Nil,
+                DefineGlobal,
+                GetGlobal,
+                Constant,
+                Add,
+                Constant,
+                Add,
+                Print,
+                Nil,
+                Return
+            ]
+        );
+        assert_eq!(
+            ops("print (true and 1) + 2;"),
+            [
+                True,
+                JumpIfFalse,
+                Pop,
+                Constant,
+                Constant,
+                Add,
+                Print,
+                Nil,
+                Return
+            ]
+        );
+
+        // As are operations the VM reports errors for
+        assert_eq!(
+            ops("print 1 + \"a\";"),
+            [Constant, Constant, Add, Print, Nil, Return]
+        );
+        assert_eq!(ops("print -nil;"), [Nil, Negate, Print, Nil, Return]);
+        assert_eq!(
+            ops("print \"a\" < \"b\";"),
+            [Constant, Constant, Less, Print, Nil, Return]
+        );
+    }
+
+    #[test]
+    fn test_constant_dedup() {
+        let (mut heap, mut globals) = (Heap::new(), Globals::new());
+        let src = "var total = 0;\n".to_owned() + &"total = total + 1;\n".repeat(300);
+        let function = Compiler::new(&src, &mut heap, &mut globals)
+            .compile()
+            .expect("source to compile");
+
+        // 0 and 1, 'total' is a global slot rather than a constant
+        assert_eq!(function.chunk.constants().len(), 2);
+        assert_eq!(globals.len(), 1);
+        assert!(!ops(&src).contains(&OpCode::GetGlobalLong));
+    }
+
+    #[test]
+    fn test_failed_compile_globals() {
+        let (mut heap, mut globals) = (Heap::new(), Globals::new());
+        let compile = |src, heap: &mut Heap, globals: &mut Globals| {
+            Compiler::new(src, heap, globals).compile().is_ok()
+        };
+
+        assert!(compile("var a = 1;", &mut heap, &mut globals));
+        assert!(!compile("var b = a; c = d +;", &mut heap, &mut globals));
+        assert_eq!(globals.len(), 1);
+        assert_eq!(globals.slot("b"), None);
+
+        assert!(compile("var c = a; print d;", &mut heap, &mut globals));
+        assert_eq!(globals.slot("a"), Some(0));
+        assert_eq!(globals.slot("c"), Some(1));
+        assert_eq!(globals.slot("d"), Some(2));
+    }
+
+    #[test]
+    fn test_upvalue_limit() {
+        let capture = |count: usize| {
+            let vars: String = (0..count).map(|i| format!("var a{i} = {i};\n")).collect();
+            let uses: String = (0..count).map(|i| format!("a{i};\n")).collect();
+            let src = format!("fun outer() {{\n{vars}fun inner() {{\n{uses}}}\n}}");
+            let (mut heap, mut globals) = (Heap::new(), Globals::new());
+            Compiler::new(&src, &mut heap, &mut globals).compile()
+        };
+
+        assert!(capture(255).is_ok());
+        let Err(InterpretError::Compile(diagnostics)) = capture(256) else {
+            panic!("256 captures should not compile");
+        };
+        assert_eq!(
+            diagnostics[0].message,
+            "Too many closure variables in function."
+        );
+    }
+
+    #[test]
+    fn test_local_names() {
+        let (mut heap, mut globals) = (Heap::new(), Globals::new());
+        let src = "{ var a = 1; { var b = a; } var c = 3; print c; }";
+        let function = Compiler::new(src, &mut heap, &mut globals)
+            .compile()
+            .expect("source to compile");
+
+        let locals = function.chunk.locals();
+        let names: Vec<_> = locals.iter().map(|l| (l.name.as_str(), l.slot)).collect();
+        assert_eq!(names, [("a", 1), ("b", 2), ("c", 2)]);
+
+        // b's slot is reused by c once b is out of scope
+        let (b, c) = (&locals[1], &locals[2]);
+        assert!(b.end.unwrap() <= c.start);
+        let in_scope: Vec<_> = function
+            .chunk
+            .locals_at(c.start)
+            .into_iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(in_scope, ["a", "c"]);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, Write},
+};
+
+use crate::{heap::Heap, object::ObjRef};
+
+/// Which instructions ran, and how often, for reporting line coverage
+#[derive(Debug, Default)]
+pub struct Coverage {
+    /// Lines of every compiled instruction, so lines that never ran are
+    /// still reported
+    lines: BTreeSet<usize>,
+    /// Every function added, which the VM keeps alive so their references
+    /// aren't reused for other functions
+    functions: Vec<ObjRef>,
+    /// Times the instruction at an offset in a function ran, with its line
+    executed: HashMap<(ObjRef, usize), (usize, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note the lines of function's instructions and those of the
+    /// functions declared in it
+    pub fn add_function(&mut self, function: ObjRef, heap: &Heap) {
+        self.functions.push(function);
+
+        let chunk = &heap.function(function).chunk;
+        let mut offset = 0;
+        while offset < chunk.len() {
+            self.lines.insert(chunk.get_line(offset));
+            offset += chunk.instruction_len(offset);
+        }
+
+        for obj in chunk.constants().iter().filter_map(|c| c.as_obj()) {
+            if heap.get(obj).as_function().is_some() {
+                self.add_function(obj, heap);
+            }
+        }
+    }
+
+    pub fn functions(&self) -> &[ObjRef] {
+        &self.functions
+    }
+
+    /// Count the instruction at offset in function, which is on line
+    pub fn record(&mut self, function: ObjRef, offset: usize, line: usize) {
+        self.executed
+            .entry((function, offset))
+            .or_insert((line, 0))
+            .1 += 1;
+    }
+
+    /// Times each line with code ran, in line order. A line runs as often
+    /// as its most executed instruction, summed over functions sharing it
+    pub fn line_hits(&self) -> BTreeMap<usize, u64> {
+        let mut per_function: HashMap<(ObjRef, usize), u64> = HashMap::new();
+        for (&(function, _), &(line, count)) in &self.executed {
+            let hits = per_function.entry((function, line)).or_default();
+            *hits = (*hits).max(count);
+        }
+
+        let mut hits: BTreeMap<_, _> = self.lines.iter().map(|&line| (line, 0)).collect();
+        for ((_, line), count) in per_function {
+            *hits.entry(line).or_default() += count;
+        }
+        hits
+    }
+
+    /// Write an lcov tracefile for the script at path
+    pub fn write_lcov(&self, path: &str, out: &mut dyn Write) -> io::Result<()> {
+        let hits = self.line_hits();
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{path}")?;
+        for (line, count) in &hits {
+            writeln!(out, "DA:{line},{count}")?;
+        }
+        writeln!(out, "LF:{}", hits.len())?;
+        writeln!(out, "LH:{}", hits.values().filter(|&&c| c > 0).count())?;
+        writeln!(out, "end_of_record")
+    }
+
+    /// Write the share of lines that ran followed by the lines that didn't
+    pub fn write_summary(&self, path: &str, out: &mut dyn Write) -> io::Result<()> {
+        let hits = self.line_hits();
+        let missed: Vec<_> = hits
+            .iter()
+            .filter(|&(_, &count)| count == 0)
+            .map(|(line, _)| line.to_string())
+            .collect();
+        let hit = hits.len() - missed.len();
+        let percent = if hits.is_empty() {
+            100.0
+        } else {
+            100.0 * hit as f64 / hits.len() as f64
+        };
+
+        writeln!(out, "{path}: {hit}/{} lines ({percent:.1}%)", hits.len())?;
+        if !missed.is_empty() {
+            writeln!(out, "not run: {}", missed.join(", "))?;
+        }
+        Ok(())
+    }
+}
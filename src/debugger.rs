@@ -0,0 +1,237 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use crate::vm::{InterpretResult, Vm};
+
+const HELP: &str = "\
+break <line>    pause before statements starting on line (b)
+delete <line>   remove the breakpoint on line (d)
+step            run to the next statement, entering calls (s)
+next            run to the next statement in this call or its caller (n)
+continue        run to the next breakpoint (c)
+stack           show the values on the stack
+locals          show the variables in scope
+globals         show the global variables
+quit            stop debugging (q)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Break(usize),
+    Delete(usize),
+    Step,
+    Next,
+    Continue,
+    Stack,
+    Locals,
+    Globals,
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let mut line = || {
+            words
+                .next()
+                .and_then(|l| l.parse().ok())
+                .ok_or_else(|| format!("Expect a line number after '{command}'."))
+        };
+
+        match command {
+            "break" | "b" => Ok(Self::Break(line()?)),
+            "delete" | "d" => Ok(Self::Delete(line()?)),
+            "step" | "s" => Ok(Self::Step),
+            "next" | "n" => Ok(Self::Next),
+            "continue" | "c" => Ok(Self::Continue),
+            "stack" => Ok(Self::Stack),
+            "locals" => Ok(Self::Locals),
+            "globals" => Ok(Self::Globals),
+            "help" | "h" => Ok(Self::Help),
+            "quit" | "q" => Ok(Self::Quit),
+            _ => Err(format!("Unknown command '{command}', try 'help'.")),
+        }
+    }
+}
+
+/// Runs a script under a Vm one statement at a time, pausing at
+/// breakpoints to inspect its variables
+pub struct Debugger<'src> {
+    vm: Vm,
+    src: &'src str,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl<'src> Debugger<'src> {
+    pub fn new(vm: Vm, src: &'src str) -> Self {
+        Self {
+            vm,
+            src,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Compile the script and pause before its first statement
+    pub fn start(&mut self, out: &mut dyn Write) -> io::Result<InterpretResult> {
+        let result = self.vm.load(self.src);
+        if result.is_ok() {
+            self.show_position(out)?;
+        }
+        Ok(result)
+    }
+
+    /// Carry out command, returning false once the script has finished or
+    /// the user quit
+    pub fn execute(&mut self, command: Command, out: &mut dyn Write) -> io::Result<bool> {
+        match command {
+            Command::Break(line) => {
+                self.breakpoints.insert(line);
+                writeln!(out, "Breakpoint at line {line}.")?;
+            }
+            Command::Delete(line) => {
+                if !self.breakpoints.remove(&line) {
+                    writeln!(out, "No breakpoint at line {line}.")?;
+                }
+            }
+            Command::Step => return self.run_until(out, |_, _, _| true),
+            Command::Next => {
+                let depth = self.vm.call_depth();
+                return self.run_until(out, |_, d, _| d <= depth);
+            }
+            Command::Continue => {
+                return self.run_until(out, |breakpoints, _, line| breakpoints.contains(&line));
+            }
+            Command::Stack => {
+                for &value in self.vm.stack() {
+                    write!(out, "[ {} ]", self.vm.format_value(value))?;
+                }
+                writeln!(out)?;
+            }
+            Command::Locals => {
+                for (name, value) in self.vm.locals() {
+                    writeln!(out, "{name} = {}", self.vm.format_value(value))?;
+                }
+            }
+            Command::Globals => {
+                for (name, value) in self.vm.globals().iter() {
+                    writeln!(out, "{name} = {}", self.vm.format_value(value))?;
+                }
+            }
+            Command::Help => writeln!(out, "{HELP}")?,
+            Command::Quit => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Run at least one instruction, then until stop says to pause before
+    /// the statement about to start. Stop gets the breakpoints and the
+    /// call depth and line of the statement
+    fn run_until(
+        &mut self,
+        out: &mut dyn Write,
+        stop: impl Fn(&BTreeSet<usize>, usize, usize) -> bool,
+    ) -> io::Result<bool> {
+        loop {
+            match self.vm.step() {
+                Ok(true) => {}
+                // The VM reports errors itself
+                Ok(false) | Err(..) => {
+                    writeln!(out, "Script finished.")?;
+                    return Ok(false);
+                }
+            }
+
+            let Some(line) = self.vm.statement_line() else {
+                continue;
+            };
+            if stop(&self.breakpoints, self.vm.call_depth(), line) {
+                self.show_position(out)?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn show_position(&self, out: &mut dyn Write) -> io::Result<()> {
+        let Some(line) = self.vm.statement_line().or(self.vm.line()) else {
+            return Ok(());
+        };
+
+        let src = self.src.lines().nth(line - 1).map_or("", str::trim);
+        writeln!(out, "[line {line}] {src}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(debugger: &mut Debugger, commands: &[&str]) -> String {
+        let mut out = Vec::new();
+        for command in commands {
+            let command = command.parse().unwrap();
+            debugger.execute(command, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("b 3".parse(), Ok(Command::Break(3)));
+        assert_eq!(" next ".parse(), Ok(Command::Next));
+        assert!("break".parse::<Command>().is_err());
+        assert!("jump 3".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_debugger() {
+        let src = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\n\
+                   var x = 1;\n{\n  var y = add(x, 2);\n  print y;\n}\nprint x; x = 2;\n\
+                   for (var i = 0; i < 2; i = i + 1) {\n  print i;\n}";
+        let vm = Vm::new().with_output(Box::new(io::sink()));
+        let mut debugger = Debugger::new(vm, src);
+
+        let mut out = Vec::new();
+        assert_eq!(debugger.start(&mut out).unwrap(), Ok(()));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[line 1] fun add(a, b) {\n"
+        );
+
+        let out = run(&mut debugger, &["break 7", "continue", "globals"]);
+        assert!(out.contains("[line 7] var y = add(x, 2);\n"));
+        assert!(out.contains("x = 1\n"));
+        assert!(out.contains("add = <fn add>\n"));
+
+        // Step into add then over its body
+        let out = run(&mut debugger, &["step", "locals", "next", "locals"]);
+        assert!(out.contains("[line 2] var sum = a + b;\na = 1\nb = 2\n"));
+        assert!(out.contains("[line 3] return sum;\na = 1\nb = 2\nsum = 3\n"));
+
+        // Back in the caller once add returns
+        let out = run(&mut debugger, &["next", "locals", "stack"]);
+        assert!(out.contains("[line 8] print y;\ny = 3\n"));
+        assert!(out.ends_with("[ <fn> ][ 3 ]\n"));
+
+        // Statements sharing a line are stepped one at a time
+        let out = run(&mut debugger, &["next", "locals", "next"]);
+        assert_eq!(out, "[line 10] print x; x = 2;\n".repeat(2));
+
+        // The copy of i made for each iteration is shown once
+        let out = run(&mut debugger, &["next", "next", "locals"]);
+        assert_eq!(
+            out,
+            "[line 11] for (var i = 0; i < 2; i = i + 1) {\n[line 12] print i;\ni = 0\n"
+        );
+
+        let mut out = Vec::new();
+        assert!(!debugger.execute(Command::Continue, &mut out).unwrap());
+        assert_eq!(String::from_utf8(out).unwrap(), "Script finished.\n");
+    }
+}
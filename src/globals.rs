@@ -0,0 +1,88 @@
+use std::{collections::HashMap, ops::Index};
+
+use crate::value::Value;
+
+/// Global variables stored by slot. The compiler resolves each name to a
+/// slot once so the VM reads and writes globals by index. Slots keep
+/// their names for error messages and outlive a single call to interpret
+#[derive(Debug, Default)]
+pub struct Globals {
+    slots: HashMap<String, usize>,
+    names: Vec<String>,
+    /// None until the variable's declaration has run
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Slot for name, adding an undefined one if name is new
+    pub fn resolve(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.slots.get(name) {
+            return slot;
+        }
+
+        let slot = self.names.len();
+        self.slots.insert(name.to_owned(), slot);
+        self.names.push(name.to_owned());
+        self.values.push(None);
+        slot
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+
+    /// Value of the global at slot, None if it hasn't been defined
+    pub fn get(&self, slot: usize) -> Option<Value> {
+        self.values.get(slot).copied().flatten()
+    }
+
+    pub fn define(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    /// Assign to the global at slot, returning false without assigning
+    /// if it hasn't been defined
+    pub fn set(&mut self, slot: usize, value: Value) -> bool {
+        match &mut self.values[slot] {
+            Some(old) => {
+                *old = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Names and values of defined globals in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Value)> + '_ {
+        self.names
+            .iter()
+            .zip(&self.values)
+            .filter_map(|(name, value)| Some((name.as_str(), (*value)?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl Index<&str> for Globals {
+    type Output = Value;
+
+    fn index(&self, name: &str) -> &Value {
+        self.slot(name)
+            .and_then(|slot| self.values[slot].as_ref())
+            .expect("a defined global")
+    }
+}
@@ -0,0 +1,391 @@
+use std::mem::{size_of, size_of_val};
+
+use crate::{
+    object::{
+        Class, Closure, Function, IString, Instance, MapKey, Obj, ObjRef, StringInterner, Upvalue,
+    },
+    value::Value,
+};
+
+/// Bytes allocated before the first collection
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Owns every object allocated while compiling and running a program.
+/// Values refer to objects through copyable handles instead of pointers.
+///
+/// Memory is reclaimed by a mark and sweep collector. The heap does not
+/// know the roots so the owner marks them before calling
+/// [`collect`](Heap::collect), and must only do so when every live value
+/// is reachable from those roots
+#[derive(Debug)]
+pub struct Heap {
+    strings: StringInterner,
+    objects: Vec<Option<Obj>>,
+    marks: Vec<bool>,
+    free: Vec<usize>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Bytes the program may use, collections happen before going over
+    limit: usize,
+    #[cfg(feature = "debug_stress_gc")]
+    allocated_since_gc: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            strings: StringInterner::new(),
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            limit: usize::MAX,
+            #[cfg(feature = "debug_stress_gc")]
+            allocated_since_gc: false,
+        }
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj_size(&obj);
+
+        #[cfg(feature = "debug_stress_gc")]
+        {
+            self.allocated_since_gc = true;
+        }
+
+        if let Some(idx) = self.free.pop() {
+            self.objects[idx] = Some(obj);
+            ObjRef(idx)
+        } else {
+            self.objects.push(Some(obj));
+            self.marks.push(false);
+            ObjRef(self.objects.len() - 1)
+        }
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        self.objects[obj.0].as_ref().expect("a live object")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        self.objects[obj.0].as_mut().expect("a live object")
+    }
+
+    /// Panics if handle does not refer to a function
+    pub fn function(&self, obj: ObjRef) -> &Function {
+        self.get(obj).as_function().expect("a function object")
+    }
+
+    /// Panics if handle does not refer to a closure
+    pub fn closure(&self, obj: ObjRef) -> &Closure {
+        self.get(obj).as_closure().expect("a closure object")
+    }
+
+    /// Panics if handle does not refer to a class
+    pub fn class(&self, obj: ObjRef) -> &Class {
+        self.get(obj).as_class().expect("a class object")
+    }
+
+    /// Panics if handle does not refer to an instance
+    pub fn instance(&self, obj: ObjRef) -> &Instance {
+        self.get(obj).as_instance().expect("an instance object")
+    }
+
+    pub fn intern<S: Into<String>>(&mut self, str: S) -> IString {
+        #[cfg(feature = "debug_stress_gc")]
+        {
+            self.allocated_since_gc = true;
+        }
+
+        self.strings.intern(str)
+    }
+
+    pub fn get_str(&self, istr: IString) -> &str {
+        self.strings.get(istr)
+    }
+
+    /// Collect whenever more than limit bytes are in use so the owner
+    /// can check it is still under the limit afterwards
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.next_gc = self.next_gc.min(limit);
+    }
+
+    /// More bytes than the limit are in use
+    pub fn over_limit(&self) -> bool {
+        self.bytes_allocated() > self.limit
+    }
+
+    /// Allocating bytes more would stay within the limit
+    pub fn fits(&self, bytes: usize) -> bool {
+        self.bytes_allocated().saturating_add(bytes) <= self.limit
+    }
+
+    /// Account for an object growing after it was allocated. Collecting
+    /// recounts every object so overestimates don't last
+    pub fn grow(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    /// Estimated bytes used by live objects and strings
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated + self.strings.bytes()
+    }
+
+    #[cfg(not(feature = "debug_stress_gc"))]
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated() > self.next_gc
+    }
+
+    /// Collect before the next instruction whenever the last one
+    /// allocated, to shake out values missing from the roots between
+    /// instructions. Several allocations within one instruction are not
+    /// collected in between
+    #[cfg(feature = "debug_stress_gc")]
+    pub fn should_collect(&self) -> bool {
+        self.allocated_since_gc || self.bytes_allocated() > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        self.marker().mark_value(value)
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        self.marker().mark_object(obj)
+    }
+
+    pub fn mark_string(&mut self, istr: IString) {
+        self.strings.mark(istr)
+    }
+
+    /// Free everything not reachable from the objects marked since the
+    /// last collection
+    pub fn collect(&mut self) {
+        self.trace_references();
+        self.sweep();
+
+        // Never wait past the limit to collect again, and when already past
+        // it collect on the next allocation
+        let bytes = self.bytes_allocated();
+        let grown = (bytes * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD);
+        self.next_gc = grown.min(self.limit.max(bytes));
+
+        #[cfg(feature = "debug_stress_gc")]
+        {
+            self.allocated_since_gc = false;
+        }
+    }
+
+    fn marker(&mut self) -> Marker<'_> {
+        Marker {
+            marks: &mut self.marks,
+            gray: &mut self.gray,
+            strings: &mut self.strings,
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            let mut marker = Marker {
+                marks: &mut self.marks,
+                gray: &mut self.gray,
+                strings: &mut self.strings,
+            };
+            let obj = self.objects[obj.0].as_ref().expect("a live object");
+            marker.blacken(obj);
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut bytes_allocated = 0;
+        for (idx, (obj, mark)) in self.objects.iter_mut().zip(&mut self.marks).enumerate() {
+            if std::mem::take(mark) {
+                bytes_allocated += obj.as_ref().map(obj_size).unwrap_or(0);
+            } else if obj.take().is_some() {
+                self.free.push(idx);
+            }
+        }
+
+        self.bytes_allocated = bytes_allocated;
+        self.strings.sweep();
+    }
+
+    /// Number of live objects, not counting strings
+    pub fn object_count(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    /// Number of live strings
+    pub fn string_count(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Human readable form of value as shown by print
+    pub fn format_value(&self, value: Value) -> String {
+        match value.as_str() {
+            Some(istr) => format!("\"{}\"", self.get_str(istr)),
+            None => self.to_string(value),
+        }
+    }
+
+    /// Value as a string without quoting strings
+    pub fn to_string(&self, value: Value) -> String {
+        if let Some(istr) = value.as_str() {
+            self.get_str(istr).to_owned()
+        } else if let Some(obj) = value.as_obj() {
+            self.format_obj(obj, &mut Vec::new())
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// Format value inside a collection. Seen holds the collections being
+    /// formatted so ones containing themselves don't recurse forever
+    fn format_nested(&self, value: Value, seen: &mut Vec<ObjRef>) -> String {
+        match value.as_obj() {
+            Some(obj) => self.format_obj(obj, seen),
+            None => self.format_value(value),
+        }
+    }
+
+    fn format_obj(&self, obj: ObjRef, seen: &mut Vec<ObjRef>) -> String {
+        match self.get(obj) {
+            Obj::Function(f) => self.format_function(f),
+            Obj::Closure(c) => self.format_function(self.function(c.function)),
+            Obj::Native(n) => format!("<native fn {}>", self.get_str(n.name)),
+            Obj::Upvalue(..) => "upvalue".to_owned(),
+            Obj::Class(c) => self.get_str(c.name).to_owned(),
+            Obj::Instance(i) => format!("{} instance", self.get_str(self.class(i.class).name)),
+            Obj::BoundMethod(b) => self.format_obj(b.method, seen),
+            Obj::List(..) if seen.contains(&obj) => "[...]".to_owned(),
+            Obj::List(l) => {
+                seen.push(obj);
+                let items: Vec<_> = l.iter().map(|&v| self.format_nested(v, seen)).collect();
+                seen.pop();
+                format!("[{}]", items.join(", "))
+            }
+            Obj::Map(..) if seen.contains(&obj) => "{...}".to_owned(),
+            Obj::Map(m) => {
+                seen.push(obj);
+                let entries: Vec<_> = m
+                    .entries()
+                    .iter()
+                    .map(|&(k, v)| {
+                        let (k, v) = (self.format_value(k), self.format_nested(v, seen));
+                        format!("{k}: {v}")
+                    })
+                    .collect();
+                seen.pop();
+                format!("{{{}}}", entries.join(", "))
+            }
+        }
+    }
+
+    fn format_function(&self, function: &Function) -> String {
+        match function.name {
+            Some(name) => format!("<fn {}>", self.get_str(name)),
+            None => "<fn>".to_owned(),
+        }
+    }
+}
+
+/// Marks objects gray and blackens them by marking what they reference.
+/// Borrows the heap's bookkeeping apart from the objects being traced
+struct Marker<'a> {
+    marks: &'a mut Vec<bool>,
+    gray: &'a mut Vec<ObjRef>,
+    strings: &'a mut StringInterner,
+}
+
+impl<'a> Marker<'a> {
+    fn mark_value(&mut self, value: Value) {
+        if let Some(istr) = value.as_str() {
+            self.strings.mark(istr)
+        } else if let Some(obj) = value.as_obj() {
+            self.mark_object(obj)
+        }
+    }
+
+    fn mark_object(&mut self, obj: ObjRef) {
+        if !std::mem::replace(&mut self.marks[obj.0], true) {
+            self.gray.push(obj)
+        }
+    }
+
+    fn blacken(&mut self, obj: &Obj) {
+        match obj {
+            Obj::Function(f) => {
+                if let Some(name) = f.name {
+                    self.strings.mark(name);
+                }
+                for &constant in f.chunk.constants() {
+                    self.mark_value(constant);
+                }
+            }
+            Obj::Closure(c) => {
+                self.mark_object(c.function);
+                for &upvalue in &c.upvalues {
+                    self.mark_object(upvalue);
+                }
+            }
+            Obj::Native(n) => self.strings.mark(n.name),
+            Obj::Upvalue(Upvalue::Closed(value)) => self.mark_value(*value),
+            Obj::Upvalue(Upvalue::Open(..)) => {}
+            Obj::Class(c) => {
+                self.strings.mark(c.name);
+                for (&name, &method) in &c.methods {
+                    self.strings.mark(name);
+                    self.mark_object(method);
+                }
+            }
+            Obj::Instance(i) => {
+                self.mark_object(i.class);
+                for (&name, &value) in &i.fields {
+                    self.strings.mark(name);
+                    self.mark_value(value);
+                }
+            }
+            Obj::BoundMethod(b) => {
+                self.mark_value(b.receiver);
+                self.mark_object(b.method);
+            }
+            Obj::List(l) => {
+                for &value in l {
+                    self.mark_value(value);
+                }
+            }
+            Obj::Map(m) => {
+                for &(key, value) in m.entries() {
+                    self.mark_value(key);
+                    self.mark_value(value);
+                }
+            }
+        }
+    }
+}
+
+/// Rough size of an object including what it owns, used to decide
+/// when to collect
+fn obj_size(obj: &Obj) -> usize {
+    let owned = match obj {
+        Obj::Function(f) => f.chunk.len() + size_of_val(f.chunk.constants()),
+        Obj::Closure(c) => c.upvalues.len() * size_of::<ObjRef>(),
+        Obj::Class(c) => c.methods.len() * size_of::<(IString, ObjRef)>(),
+        Obj::Instance(i) => i.fields.len() * size_of::<(IString, Value)>(),
+        Obj::List(l) => size_of_val(l.as_slice()),
+        Obj::Map(m) => size_of_val(m.entries()) + m.len() * size_of::<(MapKey, usize)>(),
+        Obj::Native(..) | Obj::Upvalue(..) | Obj::BoundMethod(..) => 0,
+    };
+
+    size_of::<Obj>() + owned
+}
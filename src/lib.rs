@@ -5,4 +5,14 @@ pub mod stack;
 pub mod compiler;
 pub mod scanner;
 pub mod object;
+pub mod heap;
+pub mod native;
 pub mod util;
+pub mod optimizer;
+pub mod globals;
+pub mod trace;
+pub mod debugger;
+#[cfg(feature = "profile")]
+pub mod profile;
+#[cfg(feature = "coverage")]
+pub mod coverage;
@@ -1,20 +1,132 @@
-use std::path::Path;
-
-use lox_rs::vm::{InterpretError, Vm};
+use lox_rs::{
+    debugger::{Command, Debugger},
+    trace::{JsonTracer, TextTracer},
+    vm::{InterpretError, Vm},
+};
 use rustyline::{error::ReadlineError, Editor};
 
-const HISTORY: &'static str = ".lox_history.txt";
+const HISTORY: &str = ".lox_history.txt";
+const USAGE: &str = "Usage: lox_rs [debug] [--profile] [--coverage] [--trace[=json]] [path]";
+
+/// How --trace writes each instruction to stderr
+#[derive(Clone, Copy)]
+enum Trace {
+    Text,
+    Json,
+}
+
+/// Command line flags and the script to run, flags may come in any order
+#[derive(Default)]
+struct Options {
+    /// Run the script under the debugger
+    debug: bool,
+    profile: bool,
+    /// Write line coverage of the script to <path>.info
+    coverage: bool,
+    trace: Option<Trace>,
+    path: Option<String>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Option<Self> {
+        let mut options = Self::default();
+        for arg in args {
+            match arg.as_str() {
+                "--profile" => options.profile = true,
+                "--coverage" => options.coverage = true,
+                "debug" if !options.debug && options.path.is_none() => options.debug = true,
+                "--trace" | "--trace=text" => options.trace = Some(Trace::Text),
+                "--trace=json" => options.trace = Some(Trace::Json),
+                _ if arg.starts_with("--") || options.path.is_some() => return None,
+                _ => options.path = Some(arg),
+            }
+        }
+
+        // Coverage is reported against a script file, and only a script
+        // file can be debugged
+        if (options.coverage || options.debug) && options.path.is_none() {
+            return None;
+        }
+
+        Some(options)
+    }
+
+    fn vm(&self) -> Vm {
+        let mut vm = Vm::new();
+
+        match self.trace {
+            Some(Trace::Text) => vm.set_tracer(Some(Box::new(TextTracer::new(std::io::stderr())))),
+            Some(Trace::Json) => vm.set_tracer(Some(Box::new(JsonTracer::new(std::io::stderr())))),
+            None => {}
+        }
+
+        #[cfg(feature = "profile")]
+        let vm = if self.profile { vm.with_profiler() } else { vm };
+        #[cfg(not(feature = "profile"))]
+        if self.profile {
+            eprintln!("Profiling needs lox_rs built with the 'profile' feature");
+            std::process::exit(64)
+        }
+
+        #[cfg(feature = "coverage")]
+        let vm = if self.coverage {
+            vm.with_coverage()
+        } else {
+            vm
+        };
+        #[cfg(not(feature = "coverage"))]
+        if self.coverage {
+            eprintln!("Coverage needs lox_rs built with the 'coverage' feature");
+            std::process::exit(64)
+        }
+
+        vm
+    }
+}
+
+#[cfg(feature = "profile")]
+fn print_profile(vm: &Vm) {
+    if let Some(profile) = vm.profile() {
+        if let Err(e) = profile.report(&mut std::io::stderr()) {
+            eprintln!("Failed to write profile, {e}")
+        }
+    }
+}
+
+/// Write an lcov file next to the script and a summary to stderr
+#[cfg(feature = "coverage")]
+fn write_coverage(vm: &Vm, path: &str) {
+    let Some(coverage) = vm.coverage() else {
+        return;
+    };
 
-fn repl() {
+    let info = format!("{path}.info");
+    let result = std::fs::File::create(&info)
+        .and_then(|mut file| coverage.write_lcov(path, &mut file))
+        .and_then(|_| coverage.write_summary(path, &mut std::io::stderr()));
+    if let Err(e) = result {
+        eprintln!("Failed to write coverage to {info}, {e}")
+    }
+}
+
+fn repl(options: &Options) {
     let mut rl = Editor::<()>::new();
     rl.load_history(HISTORY).unwrap_or(());
 
-    let mut vm = Vm::new();
+    let mut vm = options.vm();
+
+    // Ctrl-C at the prompt is handled by rustyline, while a script is
+    // running it stops the script instead of the REPL
+    let interrupt = vm.interrupt_handle();
+    if let Err(e) = ctrlc::set_handler(move || interrupt.interrupt()) {
+        eprintln!("Failed to install Ctrl-C handler, {e}")
+    }
+
     loop {
         let readline = rl.readline("lox> ");
         match readline {
             Ok(line) => {
-                if let Ok(_) = vm.interpret(line.as_str()) {
+                if vm.interpret(line.as_str()).is_ok() {
                     rl.add_history_entry(line.as_str());
                 }
             }
@@ -29,9 +141,12 @@ fn repl() {
     if let Err(e) = rl.save_history(HISTORY) {
         eprintln!("Failed to save history, {e}")
     }
+
+    #[cfg(feature = "profile")]
+    print_profile(&vm);
 }
 
-fn run_file<P: AsRef<Path>>(path: P) {
+fn run_file(path: &str, options: &Options) {
     let src = match std::fs::read_to_string(path) {
         Ok(src) => src,
         Err(e) => {
@@ -40,22 +155,81 @@ fn run_file<P: AsRef<Path>>(path: P) {
         }
     };
 
-    let mut vm = Vm::new();
-    match vm.interpret(&src) {
+    let mut vm = options.vm();
+    let result = vm.interpret(&src);
+
+    #[cfg(feature = "profile")]
+    print_profile(&vm);
+    #[cfg(feature = "coverage")]
+    write_coverage(&vm, path);
+
+    match result {
         Ok(_) => {}
-        Err(InterpretError::Compile) => std::process::exit(65),
-        Err(InterpretError::Runtime) => std::process::exit(70),
+        Err(InterpretError::Compile(..)) => std::process::exit(65),
+        Err(InterpretError::Runtime(..) | InterpretError::OutOfFuel) => std::process::exit(70),
     }
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => {
-            eprintln!("Usage: lox_rs [path]");
-            std::process::exit(64)
+/// Run the script under the debugger, reading commands from the terminal
+fn debug(path: &str, options: &Options) {
+    let src = match std::fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(74)
+        }
+    };
+
+    let mut debugger = Debugger::new(options.vm(), &src);
+    let mut out = std::io::stdout();
+    match debugger.start(&mut out) {
+        Ok(Ok(())) => {}
+        Ok(Err(..)) => std::process::exit(65),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(74)
+        }
+    }
+
+    let mut rl = Editor::<()>::new();
+    loop {
+        let line = match rl.readline("(debug) ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Error: {err:?}");
+                break;
+            }
+        };
+        rl.add_history_entry(line.as_str());
+
+        let command = match line.parse::<Command>() {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("{e}");
+                continue;
+            }
+        };
+        match debugger.execute(command, &mut out) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("{e}");
+                break;
+            }
         }
     }
 }
+
+fn main() {
+    let Some(options) = Options::parse(std::env::args().skip(1)) else {
+        eprintln!("{USAGE}");
+        std::process::exit(64)
+    };
+
+    match &options.path {
+        Some(path) if options.debug => debug(path, &options),
+        Some(path) => run_file(path, &options),
+        None => repl(&options),
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    heap::Heap,
+    object::{Map, NativeFn, Obj},
+    value::Value,
+};
+
+/// Natives every Vm starts with as (name, arity, function)
+pub const DEFAULTS: &[(&str, usize, NativeFn)] = &[
+    ("clock", 0, clock),
+    ("len", 1, len),
+    ("str", 1, str),
+    ("num", 1, num),
+    ("type", 1, type_of),
+    ("push", 2, push),
+    ("pop", 1, pop),
+    ("keys", 1, keys),
+    ("values", 1, values),
+    ("has", 2, has),
+];
+
+/// Seconds since the unix epoch
+fn clock(_heap: &mut Heap, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::from(now.as_secs_f64()))
+}
+
+/// Number of characters in a string, items in a list or entries in a map
+fn len(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let len = if let Some(istr) = args[0].as_str() {
+        heap.get_str(istr).chars().count()
+    } else {
+        match args[0].as_obj().map(|obj| heap.get(obj)) {
+            Some(Obj::List(l)) => l.len(),
+            Some(Obj::Map(m)) => m.len(),
+            _ => return Err("len() expects a string, list or map.".to_owned()),
+        }
+    };
+
+    Ok(Value::from(len as f64))
+}
+
+/// Append value to the end of a list
+fn push(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let list = list_mut(heap, args[0], "push")?;
+    list.push(args[1]);
+    heap.grow(std::mem::size_of::<Value>());
+    Ok(Value::NIL)
+}
+
+/// Remove and return the last item of a list
+fn pop(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let list = list_mut(heap, args[0], "pop")?;
+    list.pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_owned())
+}
+
+/// List of a map's keys in insertion order
+fn keys(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let keys = map(heap, args[0], "keys")?
+        .entries()
+        .iter()
+        .map(|&(k, _)| k)
+        .collect();
+    Ok(Value::from(heap.alloc(Obj::List(keys))))
+}
+
+/// List of a map's values in insertion order
+fn values(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let values = map(heap, args[0], "values")?
+        .entries()
+        .iter()
+        .map(|&(_, v)| v)
+        .collect();
+    Ok(Value::from(heap.alloc(Obj::List(values))))
+}
+
+/// Whether a map has an entry for key
+fn has(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let map = map(heap, args[0], "has")?;
+    Ok(Value::from(map.contains_key(args[1])))
+}
+
+fn map<'a>(heap: &'a Heap, value: Value, name: &str) -> Result<&'a Map, String> {
+    value
+        .as_obj()
+        .and_then(|obj| heap.get(obj).as_map())
+        .ok_or_else(|| format!("{name}() expects a map."))
+}
+
+fn list_mut<'a>(
+    heap: &'a mut Heap,
+    value: Value,
+    name: &str,
+) -> Result<&'a mut Vec<Value>, String> {
+    value
+        .as_obj()
+        .and_then(|obj| heap.get_mut(obj).as_list_mut())
+        .ok_or_else(|| format!("{name}() expects a list."))
+}
+
+fn str(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let str = heap.to_string(args[0]);
+    Ok(Value::from(heap.intern(str)))
+}
+
+/// Parse a string into a number, numbers are returned unchanged
+fn num(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    if args[0].as_num().is_some() {
+        return Ok(args[0]);
+    }
+
+    let istr = args[0]
+        .as_str()
+        .ok_or_else(|| "num() expects a string or number.".to_owned())?;
+    let str = heap.get_str(istr);
+    str.trim()
+        .parse::<f64>()
+        .map(Value::from)
+        .map_err(|_| format!("Can't convert '{str}' to a number."))
+}
+
+/// Name of the value's type
+fn type_of(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let value = args[0];
+    let name = if let Some(obj) = value.as_obj() {
+        match heap.get(obj) {
+            Obj::Function(..) | Obj::Closure(..) | Obj::Native(..) | Obj::BoundMethod(..) => {
+                "function"
+            }
+            Obj::Class(..) => "class",
+            Obj::Instance(..) => "instance",
+            Obj::List(..) => "list",
+            Obj::Map(..) => "map",
+            Obj::Upvalue(..) => "upvalue",
+        }
+    } else if value.as_str().is_some() {
+        "string"
+    } else if value.as_num().is_some() {
+        "number"
+    } else if value.as_bool().is_some() {
+        "bool"
+    } else {
+        "nil"
+    };
+
+    Ok(Value::from(heap.intern(name)))
+}
@@ -1,13 +1,21 @@
 use std::collections::HashMap;
 
+use crate::{chunk::Chunk, heap::Heap, value::Value};
+
 /// Interned string type
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct IString(usize);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IString(pub(crate) usize);
 
-#[derive(Debug)]
+/// Strings are only reclaimed by [`sweep`](StringInterner::sweep), which
+/// frees every string not marked since the previous sweep. Freed slots
+/// are reused by later strings
+#[derive(Debug, Default)]
 pub struct StringInterner {
     map: HashMap<String, IString>,
-    vals: Vec<String>,
+    vals: Vec<Option<String>>,
+    marks: Vec<bool>,
+    free: Vec<usize>,
+    bytes: usize,
 }
 
 impl StringInterner {
@@ -15,23 +23,346 @@ impl StringInterner {
         Self {
             map: HashMap::new(),
             vals: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            bytes: 0,
         }
     }
 
     pub fn intern<S: Into<String>>(&mut self, str: S) -> IString {
         let str = str.into();
         if let Some(val) = self.map.get(&str) {
-            *val
-        } else {
-            let istr = IString(self.vals.len());
-            self.vals.push(str.clone());
-            self.map.insert(str, istr);
-            istr
+            return *val;
         }
+
+        self.bytes += str.len();
+        let istr = if let Some(idx) = self.free.pop() {
+            self.vals[idx] = Some(str.clone());
+            IString(idx)
+        } else {
+            self.vals.push(Some(str.clone()));
+            self.marks.push(false);
+            IString(self.vals.len() - 1)
+        };
+
+        self.map.insert(str, istr);
+        istr
     }
 
     pub fn get(&self, istr: IString) -> &str {
-        &self.vals[istr.0]
+        self.vals[istr.0].as_deref().expect("a live string")
+    }
+
+    pub fn mark(&mut self, istr: IString) {
+        self.marks[istr.0] = true
+    }
+
+    /// Free unmarked strings and clear marks for the next collection
+    pub fn sweep(&mut self) {
+        for (idx, (val, mark)) in self.vals.iter_mut().zip(&mut self.marks).enumerate() {
+            if std::mem::take(mark) {
+                continue;
+            }
+
+            if let Some(str) = val.take() {
+                self.bytes -= str.len();
+                self.map.remove(&str);
+                self.free.push(idx);
+            }
+        }
+    }
+
+    /// Number of live strings
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Total length of live strings
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// Handle to an object allocated on the [`Heap`](crate::heap::Heap)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(pub(crate) usize);
+
+#[derive(Debug)]
+pub enum Obj {
+    Function(Function),
+    Closure(Closure),
+    Native(Native),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    List(Vec<Value>),
+    Map(Map),
+}
+
+impl Obj {
+    pub fn as_function(&self) -> Option<&Function> {
+        match self {
+            Self::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_function_mut(&mut self) -> Option<&mut Function> {
+        match self {
+            Self::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&Closure> {
+        match self {
+            Self::Closure(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&Class> {
+        match self {
+            Self::Class(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_class_mut(&mut self) -> Option<&mut Class> {
+        match self {
+            Self::Class(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&Instance> {
+        match self {
+            Self::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance_mut(&mut self) -> Option<&mut Instance> {
+        match self {
+            Self::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            Self::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Self::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&Map> {
+        match self {
+            Self::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn as_map_mut(&mut self) -> Option<&mut Map> {
+        match self {
+            Self::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self) -> Option<&Upvalue> {
+        match self {
+            Self::Upvalue(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self) -> Option<&mut Upvalue> {
+        match self {
+            Self::Upvalue(u) => Some(u),
+            _ => None,
+        }
+    }
+}
+
+/// Compiled function, the top level script and anonymous functions
+/// have no name
+#[derive(Debug, Default)]
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<IString>,
+}
+
+impl Function {
+    pub fn new(name: Option<IString>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+        }
+    }
+}
+
+/// Host function callable from scripts. Arguments are checked against
+/// arity before the function runs, an error message becomes a runtime error
+pub type NativeFn = fn(&mut Heap, &[Value]) -> Result<Value, String>;
+
+#[derive(Debug)]
+pub struct Native {
+    pub name: IString,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+/// Function paired with the variables it captured from enclosing scopes
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// Captured variable. While the variable is still on the stack the upvalue
+/// points at its slot, once the slot is popped the value moves in here
+#[derive(Debug, Clone, Copy)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// Methods map names to the closures implementing them
+#[derive(Debug)]
+pub struct Class {
+    pub name: IString,
+    pub methods: HashMap<IString, ObjRef>,
+}
+
+impl Class {
+    pub fn new(name: IString) -> Self {
+        Self {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<IString, Value>,
+}
+
+impl Instance {
+    pub fn new(class: ObjRef) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+/// Method closure accessed from an instance, remembering the
+/// instance to use as 'this' when it is called
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// Hashable form of a map key. Keys are equal exactly when [`Value::eq`]
+/// holds for them: numbers with the same bits once -0 is made 0, and
+/// interned strings with the same handle. NaN equals nothing so it can't
+/// be a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Num(u64),
+    Str(IString),
+}
+
+impl MapKey {
+    /// None for objects and NaN, which can't be keys
+    pub fn new(value: Value) -> Option<Self> {
+        if value.is_nil() {
+            Some(Self::Nil)
+        } else if let Some(b) = value.as_bool() {
+            Some(Self::Bool(b))
+        } else if let Some(n) = value.as_num() {
+            // -0 + 0 is 0, leaving every other number as it was
+            (!n.is_nan()).then(|| Self::Num((n + 0.0).to_bits()))
+        } else {
+            value.as_str().map(Self::Str)
+        }
+    }
+}
+
+/// Entries keep insertion order, with an index from each key to its
+/// entry
+#[derive(Debug, Default)]
+pub struct Map {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /// Only values compared by value can be keys
+    pub fn is_valid_key(key: Value) -> bool {
+        MapKey::new(key).is_some()
+    }
+
+    pub fn get(&self, key: Value) -> Option<Value> {
+        let idx = self.index.get(&MapKey::new(key)?)?;
+        Some(self.entries[*idx].1)
+    }
+
+    /// Panics if key is not a valid key
+    pub fn insert(&mut self, key: Value, value: Value) {
+        let map_key = MapKey::new(key).expect("a valid map key");
+        match self.index.get(&map_key) {
+            Some(&idx) => self.entries[idx].1 = value,
+            None => {
+                self.index.insert(map_key, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: Value) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 }
 
@@ -45,4 +376,39 @@ mod tests {
         let a = interner.intern("this is a test");
         assert_eq!(interner.get(a), "this is a test");
     }
+
+    #[test]
+    fn test_map_keys() {
+        let mut map = Map::new();
+        map.insert(Value::from(0.0), Value::from(1.0));
+        map.insert(Value::from(-0.0), Value::from(2.0));
+        map.insert(Value::NIL, Value::from(true));
+        assert_eq!(map.len(), 2);
+        assert!(map.get(Value::from(0.0)).unwrap().eq(&Value::from(2.0)));
+        assert!(map.contains_key(Value::NIL));
+        assert!(!map.contains_key(Value::from(false)));
+
+        // Keys match only when the values are equal
+        map.insert(Value::from(0.1 + 0.2), Value::NIL);
+        assert!(!map.contains_key(Value::from(0.3)));
+        assert!(!Value::from(0.1 + 0.2).eq(&Value::from(0.3)));
+        assert!(!Map::is_valid_key(Value::from(f64::NAN)));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut interner = StringInterner::new();
+        let kept = interner.intern("kept");
+        interner.intern("garbage");
+
+        interner.mark(kept);
+        interner.sweep();
+        assert_eq!(interner.len(), 1);
+        assert_eq!(interner.get(kept), "kept");
+
+        // Slot of the freed string is reused
+        let reused = interner.intern("new");
+        assert_eq!(reused, IString(1));
+        assert_eq!(interner.intern("kept"), kept);
+    }
 }
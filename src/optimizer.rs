@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    chunk::{Chunk, OpCode},
+    heap::Heap,
+    util::split_u16,
+};
+
+/// Instruction sequences the compiler commonly emits and the single
+/// instruction replacing each. The fused instruction takes the operands
+/// of the sequence in order
+const FUSIONS: &[(&[OpCode], OpCode)] = &[
+    (&[OpCode::Equal, OpCode::Not], OpCode::NotEqual),
+    (&[OpCode::Less, OpCode::Not], OpCode::GreaterEqual),
+    (&[OpCode::Greater, OpCode::Not], OpCode::LessEqual),
+    (&[OpCode::SetLocal, OpCode::Pop], OpCode::SetLocalPop),
+    (
+        &[OpCode::GetLocal, OpCode::Constant, OpCode::Add],
+        OpCode::GetLocalAddConstant,
+    ),
+];
+
+/// Replace sequences in chunk, and the chunks of functions declared in
+/// it, with superinstructions. Jumps are retargeted to the rewritten
+/// code and each instruction keeps the line it had
+pub fn optimize(chunk: &mut Chunk, heap: &mut Heap) {
+    let functions: Vec<_> = chunk
+        .constants()
+        .iter()
+        .filter_map(|c| c.as_obj())
+        .filter(|&obj| heap.get(obj).as_function().is_some())
+        .collect();
+
+    for obj in functions {
+        let function = heap.get_mut(obj).as_function_mut().expect("a function");
+        let mut nested = std::mem::take(&mut function.chunk);
+        optimize(&mut nested, heap);
+        heap.get_mut(obj)
+            .as_function_mut()
+            .expect("a function")
+            .chunk = nested;
+    }
+
+    peephole(chunk);
+}
+
+fn peephole(chunk: &mut Chunk) {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.len() {
+        starts.push(offset);
+        offset += chunk.instruction_len(offset);
+    }
+
+    // Code jumped to must still start an instruction so it can't be fused
+    // into the one before it, nor can code where a statement begins or a
+    // local's scope changes
+    let targets: HashSet<_> = starts
+        .iter()
+        .filter_map(|&s| chunk.jump_target(s))
+        .chain(chunk.statement_offsets())
+        .chain(
+            chunk
+                .locals()
+                .iter()
+                .flat_map(|l| [Some(l.start), l.end])
+                .flatten(),
+        )
+        .collect();
+
+    let mut out = Chunk::new();
+    let mut new_offsets = HashMap::new();
+    let mut jumps = Vec::new();
+
+    let mut idx = 0;
+    while idx < starts.len() {
+        let start = starts[idx];
+        new_offsets.insert(start, out.len());
+
+        let (fused, count) = match fusion_at(chunk, &starts[idx..], &targets) {
+            Some((fused, count)) => (Some(fused), count),
+            None => (None, 1),
+        };
+        let sequence = &starts[idx..idx + count];
+
+        // Sequences take the line of their last instruction, which for
+        // GetLocalAddConstant is the Add that can fail
+        let line = chunk.get_line(*sequence.last().expect("an instruction"));
+        if let Some(fused) = fused {
+            out.write_chunk(fused, line);
+            for &s in sequence {
+                copy(chunk, &mut out, s + 1..s + chunk.instruction_len(s), line);
+            }
+        } else {
+            if let Some(target) = chunk.jump_target(start) {
+                jumps.push((out.len(), target));
+            }
+            copy(
+                chunk,
+                &mut out,
+                start..start + chunk.instruction_len(start),
+                line,
+            );
+        }
+
+        idx += count;
+    }
+    new_offsets.insert(chunk.len(), out.len());
+
+    for (at, target) in jumps {
+        let target = new_offsets[&target];
+        let distance = match out.get_op(at) {
+            Some(OpCode::Loop) => at + 3 - target,
+            _ => target - (at + 3),
+        };
+
+        // Fusing only removes code so distances never grow past u16
+        let (d1, d2) = split_u16(distance as u16);
+        *out.get_byte_mut(at + 1).expect("jump byte") = d1;
+        *out.get_byte_mut(at + 2).expect("jump byte") = d2;
+    }
+
+    chunk.replace_code(out);
+    chunk.remap_debug_info(&new_offsets);
+}
+
+/// Fused instruction and the number of instructions it replaces if a
+/// sequence starts at the first of starts
+fn fusion_at(chunk: &Chunk, starts: &[usize], targets: &HashSet<usize>) -> Option<(OpCode, usize)> {
+    FUSIONS.iter().find_map(|&(sequence, fused)| {
+        let matches = sequence.len() <= starts.len()
+            && sequence
+                .iter()
+                .zip(starts)
+                .enumerate()
+                .all(|(i, (&op, &start))| {
+                    chunk.get_op(start) == Some(op) && (i == 0 || !targets.contains(&start))
+                });
+        matches.then_some((fused, sequence.len()))
+    })
+}
+
+fn copy(chunk: &Chunk, out: &mut Chunk, range: std::ops::Range<usize>, line: usize) {
+    for offset in range {
+        out.write_chunk(chunk.get_byte(offset).expect("a byte"), line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, globals::Globals, object::Function};
+
+    fn compile(src: &str, heap: &mut Heap) -> Function {
+        Compiler::new(src, heap, &mut Globals::new())
+            .compile()
+            .expect("source to compile")
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<OpCode> {
+        let mut ops = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.len() {
+            ops.push(chunk.get_op(offset).unwrap());
+            offset += chunk.instruction_len(offset);
+        }
+        ops
+    }
+
+    #[test]
+    fn test_fusion() {
+        let mut heap = Heap::new();
+        let mut function = compile("{ var a = 1; a = a + 2; print a != 1; }", &mut heap);
+        optimize(&mut function.chunk, &mut heap);
+
+        assert_eq!(
+            ops(&function.chunk),
+            [
+                OpCode::Constant,
+                OpCode::GetLocalAddConstant,
+                OpCode::SetLocalPop,
+                OpCode::GetLocal,
+                OpCode::Constant,
+                OpCode::NotEqual,
+                OpCode::Print,
+                OpCode::Pop,
+                OpCode::Nil,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jumps() {
+        let mut heap = Heap::new();
+        let src = "
+            for (var i = 0; !(i >= 3); i = i + 1) {
+                if (i != 1) print i;
+            }";
+        let mut function = compile(src, &mut heap);
+        let before = function.chunk.len();
+        optimize(&mut function.chunk, &mut heap);
+        assert!(function.chunk.len() < before);
+
+        // Every jump still lands on an instruction
+        let mut starts = HashSet::new();
+        let mut offset = 0;
+        while offset < function.chunk.len() {
+            starts.insert(offset);
+            offset += function.chunk.instruction_len(offset);
+        }
+        starts.insert(function.chunk.len());
+        for &start in &starts {
+            if let Some(target) = function.chunk.jump_target(start) {
+                assert!(starts.contains(&target), "jump from {start} to {target}");
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::chunk::OpCode;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LineStats {
+    pub count: u64,
+    /// Wall time from the start of each instruction on the line to the
+    /// start of the next instruction
+    pub time: Duration,
+}
+
+/// Execution counts per opcode and per source line, with the time spent
+/// on each line
+#[derive(Debug)]
+pub struct Profile {
+    ops: [u64; 256],
+    lines: HashMap<usize, LineStats>,
+    /// Line of the instruction being timed and when it started
+    current: Option<(usize, Instant)>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            ops: [0; 256],
+            lines: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// Count op about to run on line, ending the timing of the
+    /// instruction before it
+    pub fn record(&mut self, op: OpCode, line: usize) {
+        let now = Instant::now();
+        self.stop_at(now);
+        self.current = Some((line, now));
+
+        self.ops[u8::from(op) as usize] += 1;
+        self.lines.entry(line).or_default().count += 1;
+    }
+
+    /// End the timing of the last instruction, so time between runs
+    /// isn't charged to it
+    pub fn pause(&mut self) {
+        self.stop_at(Instant::now())
+    }
+
+    fn stop_at(&mut self, now: Instant) {
+        if let Some((line, start)) = self.current.take() {
+            self.lines.entry(line).or_default().time += now - start;
+        }
+    }
+
+    /// Times op was executed
+    pub fn op_count(&self, op: OpCode) -> u64 {
+        self.ops[u8::from(op) as usize]
+    }
+
+    pub fn line(&self, line: usize) -> Option<LineStats> {
+        self.lines.get(&line).copied()
+    }
+
+    /// Write executed opcodes by count and lines by time, most first
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut ops: Vec<_> = (0..=u8::MAX)
+            .filter_map(|byte| Some((OpCode::from_byte(byte)?, self.ops[byte as usize])))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        ops.sort_by_key(|&(_, count)| Reverse(count));
+
+        let total: u64 = ops.iter().map(|&(_, count)| count).sum();
+        writeln!(out, "== opcodes ==")?;
+        writeln!(out, "{:>12} {:>7}  opcode", "count", "%")?;
+        for (op, count) in ops {
+            let percent = 100.0 * count as f64 / total as f64;
+            writeln!(out, "{count:>12} {percent:>6.2}%  {op:?}")?;
+        }
+
+        let mut lines: Vec<_> = self.lines.iter().collect();
+        lines.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+
+        writeln!(out, "== lines ==")?;
+        writeln!(out, "{:>6} {:>12} {:>14}", "line", "count", "time")?;
+        for (line, stats) in lines {
+            writeln!(out, "{line:>6} {:>12} {:>14.3?}", stats.count, stats.time)?;
+        }
+
+        Ok(())
+    }
+}
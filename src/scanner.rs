@@ -6,13 +6,16 @@ macro_rules! alpha {
     };
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum TokenType {
     // Single character
     LParen = 0,
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -34,11 +37,14 @@ pub enum TokenType {
     // Literals
     Identifier,
     String,
+    Interpolation,
     Number,
 
     // Keywords
     And,
+    Break,
     Class,
+    Continue,
     Else,
     False,
     For,
@@ -55,27 +61,34 @@ pub enum TokenType {
     While,
 
     Error,
+    #[default]
     Eof,
 }
 
-impl Default for TokenType {
-    fn default() -> Self {
-        Self::Eof
-    }
-}
-
 #[derive(Default, Clone, Copy, Debug)]
 pub struct Token<'input> {
     pub typ: TokenType,
     pub src: &'input str,
     pub line: usize,
+    /// Column of the first character, starting from 1
+    pub column: usize,
 }
 
+#[derive(Clone)]
 pub struct Scanner<'input> {
     src: &'input str,
     start: usize,
     current: usize,
     line: usize,
+    /// Offset of the first character on the current line
+    line_start: usize,
+    /// Characters consumed since line_start
+    line_chars: usize,
+    /// Line and column where the token being scanned starts
+    start_line: usize,
+    start_column: usize,
+    /// Unclosed braces inside each '${' being scanned, innermost last
+    interpolations: Vec<usize>,
 }
 
 impl<'input> Scanner<'input> {
@@ -85,23 +98,52 @@ impl<'input> Scanner<'input> {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            line_chars: 0,
+            start_line: 1,
+            start_column: 1,
+            interpolations: Vec::new(),
         }
     }
 
     pub fn scan_token(&mut self) -> Token<'input> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.line_chars + 1;
 
         if self.is_at_end() {
+            if !self.interpolations.is_empty() {
+                self.interpolations.clear();
+                return self.error_token("Unterminated '${' in string.");
+            }
             return self.make_token(TokenType::Eof);
         }
 
         match self.advance() {
             '(' => self.make_token(TokenType::LParen),
             ')' => self.make_token(TokenType::RParen),
-            '{' => self.make_token(TokenType::LBrace),
-            '}' => self.make_token(TokenType::RBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RBrace)
+                }
+                None => self.make_token(TokenType::RBrace),
+            },
+            '[' => self.make_token(TokenType::LBracket),
+            ']' => self.make_token(TokenType::RBracket),
             ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
@@ -147,10 +189,20 @@ impl<'input> Scanner<'input> {
         }
     }
 
+    /// Scan string text up to the closing quote or the next '${'. The
+    /// lexeme starts with the opening '"', or the '}' ending the previous
+    /// interpolation, and ends with '"' or '${'
     fn string(&mut self) -> Token<'input> {
         while self.peek() != '"' && !self.is_at_end() {
+            if self.peek() == '$' && self.peek_next() == '{' {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
+            }
+
             if self.peek() == '\n' {
-                self.line += 1;
+                self.new_line();
             }
             self.advance();
         }
@@ -192,7 +244,12 @@ impl<'input> Scanner<'input> {
     fn identifier_type(&self) -> TokenType {
         match self.char_at(self.start) {
             'a' => self.check_keyword(1, "nd", TokenType::And),
-            'c' => self.check_keyword(1, "lass", TokenType::Class),
+            'b' => self.check_keyword(1, "reak", TokenType::Break),
+            'c' => match self.char_at(self.start + 1) {
+                'l' => self.check_keyword(2, "ass", TokenType::Class),
+                'o' => self.check_keyword(2, "ntinue", TokenType::Continue),
+                _ => TokenType::Identifier,
+            },
             'e' => self.check_keyword(1, "lse", TokenType::Else),
             'f' => match self.char_at(self.start + 1) {
                 'a' => self.check_keyword(2, "lse", TokenType::False),
@@ -233,16 +290,12 @@ impl<'input> Scanner<'input> {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
+                    self.new_line();
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -250,6 +303,13 @@ impl<'input> Scanner<'input> {
         }
     }
 
+    /// Called on the newline before it is consumed
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current + 1;
+        self.line_chars = 0;
+    }
+
     fn char_at(&self, idx: usize) -> char {
         self.src[idx..].chars().next().unwrap_or('\0')
     }
@@ -263,10 +323,20 @@ impl<'input> Scanner<'input> {
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
+        self.bump();
         self.char_at(self.current - 1)
     }
 
+    /// Consume a byte, counting it towards the column unless it is the
+    /// newline ending the previous line or continues a UTF-8 character
+    fn bump(&mut self) {
+        self.current += 1;
+        let continuation = self.src.as_bytes()[self.current - 1] & 0xC0 == 0x80;
+        if self.current > self.line_start && !continuation {
+            self.line_chars += 1;
+        }
+    }
+
     fn is_at_end(&self) -> bool {
         self.peek() == '\0'
     }
@@ -276,7 +346,7 @@ impl<'input> Scanner<'input> {
             return false;
         }
 
-        self.current += 1;
+        self.bump();
         true
     }
 
@@ -284,7 +354,8 @@ impl<'input> Scanner<'input> {
         Token {
             typ,
             src: &self.src[self.start..self.current],
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
         }
     }
 
@@ -292,13 +363,14 @@ impl<'input> Scanner<'input> {
         Token {
             typ: TokenType::Error,
             src: msg,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
         }
     }
 }
 
 fn is_digit(c: char) -> bool {
-    matches!(c, '0'..='9')
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
@@ -317,10 +389,10 @@ mod tests {
 
     #[test]
     fn test_scanner() {
-        let src = r#"(){},.-+;/*   ! != == = > >= < <= test "string" 5.0
-                     and class else false for fun if nil or print return
+        let src = r#"(){}[]:,.-+;/*   ! != == = > >= < <= test "string" "a${b}c" 5.0
+                     and break class continue else false for fun if nil or print return
                      super this true var while"#;
-        let mut scanner = Scanner::new(&src);
+        let mut scanner = Scanner::new(src);
 
         let tokens = [
             // Single character
@@ -328,6 +400,9 @@ mod tests {
             TokenType::RParen,
             TokenType::LBrace,
             TokenType::RBrace,
+            TokenType::LBracket,
+            TokenType::RBracket,
+            TokenType::Colon,
             TokenType::Comma,
             TokenType::Dot,
             TokenType::Minus,
@@ -347,10 +422,15 @@ mod tests {
             // Literals
             TokenType::Identifier,
             TokenType::String,
+            TokenType::Interpolation,
+            TokenType::Identifier,
+            TokenType::String,
             TokenType::Number,
             // Keywords
             TokenType::And,
+            TokenType::Break,
             TokenType::Class,
+            TokenType::Continue,
             TokenType::Else,
             TokenType::False,
             TokenType::For,
@@ -371,4 +451,41 @@ mod tests {
             assert_eq!(scanner.scan_token().typ, typ);
         }
     }
+
+    #[test]
+    fn test_interpolation() {
+        let mut scanner = Scanner::new(r#""a ${ {"k": "${x}"} } b" "${ 1"#);
+
+        let tokens = [
+            (TokenType::Interpolation, r#""a ${"#),
+            (TokenType::LBrace, "{"),
+            (TokenType::String, r#""k""#),
+            (TokenType::Colon, ":"),
+            (TokenType::Interpolation, r#""${"#),
+            (TokenType::Identifier, "x"),
+            (TokenType::String, r#"}""#),
+            (TokenType::RBrace, "}"),
+            (TokenType::String, r#"} b""#),
+            (TokenType::Interpolation, r#""${"#),
+            (TokenType::Number, "1"),
+            (TokenType::Error, "Unterminated '${' in string."),
+            (TokenType::Eof, ""),
+        ];
+
+        for (typ, src) in tokens {
+            let token = scanner.scan_token();
+            assert_eq!((token.typ, token.src), (typ, src));
+        }
+    }
+
+    #[test]
+    fn test_columns() {
+        let mut scanner = Scanner::new("var a;\n  print \"two\nlines\" x;");
+
+        let positions = [(1, 1), (1, 5), (1, 6), (2, 3), (2, 9), (3, 8), (3, 9)];
+        for (line, column) in positions {
+            let token = scanner.scan_token();
+            assert_eq!((token.line, token.column), (line, column), "{token}");
+        }
+    }
 }
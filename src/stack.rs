@@ -36,11 +36,40 @@ impl<T> Stack<T> {
         self.data.get(idx)
     }
 
+    /// Top count values, oldest first
+    pub fn top(&self, count: usize) -> &[T] {
+        &self.data[self.data.len() - count..]
+    }
+
+    /// Every value, oldest first
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Discard every value at or above idx
+    pub fn truncate(&mut self, idx: usize) {
+        self.data.truncate(idx)
+    }
+
     pub fn reset(&mut self) {
         self.data.clear()
     }
 }
 
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a, T> IntoIterator for &'a Stack<T> {
     type Item = &'a T;
     type IntoIter = std::slice::Iter<'a, T>;
@@ -0,0 +1,134 @@
+use std::io::{self, Write};
+
+use crate::{
+    chunk::{Chunk, OpCode},
+    heap::Heap,
+    value::Value,
+};
+
+/// The instruction about to run and the stack it runs on
+pub struct TraceEvent<'a> {
+    pub chunk: &'a Chunk,
+    pub offset: usize,
+    pub op: OpCode,
+    /// Bytes following the opcode
+    pub operands: &'a [u8],
+    pub line: usize,
+    /// Values on the stack, oldest first
+    pub stack: &'a [Value],
+    /// Heap the stack's objects live in, for formatting them
+    pub heap: &'a Heap,
+}
+
+impl<'a> TraceEvent<'a> {
+    /// Panics if there is no instruction at offset
+    pub fn new(chunk: &'a Chunk, offset: usize, stack: &'a [Value], heap: &'a Heap) -> Self {
+        Self {
+            chunk,
+            offset,
+            op: chunk.get_op(offset).expect("an instruction"),
+            operands: chunk.operands(offset),
+            line: chunk.get_line(offset),
+            stack,
+            heap,
+        }
+    }
+}
+
+/// Receives every instruction a Vm runs, see
+/// [`set_tracer`](crate::vm::Vm::set_tracer). Errors stop the script with
+/// a runtime error
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent) -> io::Result<()>;
+}
+
+/// Writes the stack followed by the disassembled instruction, like the
+/// debug_trace_execution feature
+pub struct TextTracer<W> {
+    out: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) -> io::Result<()> {
+        write!(self.out, "\t\t")?;
+        for &value in event.stack {
+            write!(self.out, "[ {} ]", event.heap.format_value(value))?;
+        }
+        writeln!(self.out)?;
+
+        event
+            .chunk
+            .disassemble_instruction(event.offset, &mut self.out)?;
+        Ok(())
+    }
+}
+
+/// Writes each instruction as a JSON object on its own line, e.g.
+/// `{"offset":0,"op":"Constant","operands":[0],"line":1,"stack":[]}`
+pub struct JsonTracer<W> {
+    out: W,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let operands: Vec<_> = event.operands.iter().map(u8::to_string).collect();
+        let stack: Vec<_> = event
+            .stack
+            .iter()
+            .map(|&value| json_string(&event.heap.format_value(value)))
+            .collect();
+
+        writeln!(
+            self.out,
+            r#"{{"offset":{},"op":"{:?}","operands":[{}],"line":{},"stack":[{}]}}"#,
+            event.offset,
+            event.op,
+            operands.join(","),
+            event.line,
+            stack.join(","),
+        )
+    }
+}
+
+/// Quote str as a JSON string
+fn json_string(str: &str) -> String {
+    let mut quoted = String::with_capacity(str.len() + 2);
+    quoted.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("abc"), r#""abc""#);
+        assert_eq!(json_string("\"a\"\n"), r#""\"a\"\n""#);
+        assert_eq!(json_string("\\\u{1}"), r#""\\\u0001""#);
+    }
+}
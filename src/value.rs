@@ -1,74 +1,272 @@
 use std::fmt::Display;
 
-use crate::object::IString;
+pub use repr::Value;
 
-const FLOAT_TOL: f64 = 1e-9;
+/// Values are an enum of the possible types
+#[cfg(not(feature = "nan_boxing"))]
+mod repr {
+    use crate::object::{IString, ObjRef};
 
-#[derive(Debug, Copy, Clone)]
-pub enum Value {
-    Nil,
-    Bool(bool),
-    Num(f64),
-    String(IString),
-}
+    #[derive(Debug, Copy, Clone)]
+    pub struct Value(Repr);
 
-impl Value {
-    pub fn as_num(&self) -> Option<f64> {
-        if let Self::Num(n) = self {
-            Some(*n)
-        } else {
-            None
+    #[derive(Debug, Copy, Clone)]
+    enum Repr {
+        Nil,
+        Bool(bool),
+        Num(f64),
+        String(IString),
+        Obj(ObjRef),
+    }
+
+    impl Value {
+        pub const NIL: Value = Value(Repr::Nil);
+
+        pub fn is_nil(&self) -> bool {
+            matches!(self.0, Repr::Nil)
+        }
+
+        pub fn as_bool(&self) -> Option<bool> {
+            match self.0 {
+                Repr::Bool(b) => Some(b),
+                _ => None,
+            }
+        }
+
+        pub fn as_num(&self) -> Option<f64> {
+            match self.0 {
+                Repr::Num(n) => Some(n),
+                _ => None,
+            }
+        }
+
+        pub fn as_str(&self) -> Option<IString> {
+            match self.0 {
+                Repr::String(s) => Some(s),
+                _ => None,
+            }
+        }
+
+        pub fn as_obj(&self) -> Option<ObjRef> {
+            match self.0 {
+                Repr::Obj(o) => Some(o),
+                _ => None,
+            }
+        }
+
+        #[allow(clippy::should_implement_trait)]
+        pub fn eq(&self, other: &Value) -> bool {
+            match (self.0, other.0) {
+                (Repr::Nil, Repr::Nil) => true,
+                (Repr::Bool(a), Repr::Bool(b)) => a == b,
+                (Repr::Num(a), Repr::Num(b)) => a == b,
+                (Repr::String(a), Repr::String(b)) => a == b,
+                (Repr::Obj(a), Repr::Obj(b)) => a == b,
+                _ => false,
+            }
         }
     }
 
-    pub fn as_str(&self) -> Option<IString> {
-        if let Self::String(s) = self {
-            Some(*s)
-        } else {
-            None
+    impl From<f64> for Value {
+        fn from(n: f64) -> Self {
+            Value(Repr::Num(n))
         }
     }
 
-    pub fn is_falsey(&self) -> bool {
-        matches!(self, Self::Nil | Self::Bool(false))
+    impl From<bool> for Value {
+        fn from(b: bool) -> Self {
+            Value(Repr::Bool(b))
+        }
     }
 
-    pub fn eq(&self, other: &Value) -> bool {
-        match (self, other) {
-            (Value::Nil, _) => true,
-            (Value::Bool(a), Value::Bool(b)) => a == b,
-            (Value::Num(a), Value::Num(b)) => (a - b) < FLOAT_TOL,
-            (Value::String(a), Value::String(b)) => a == b,
-            _ => false,
+    impl From<IString> for Value {
+        fn from(s: IString) -> Self {
+            Value(Repr::String(s))
+        }
+    }
+
+    impl From<ObjRef> for Value {
+        fn from(o: ObjRef) -> Self {
+            Value(Repr::Obj(o))
         }
     }
 }
 
-impl From<f64> for Value {
-    fn from(f: f64) -> Self {
-        Value::Num(f)
+/// Values are packed into the unused bits of a quiet NaN. Anything that
+/// isn't such a NaN is a number, otherwise the sign bit marks a handle,
+/// with a further bit separating strings from objects, and the low bits
+/// tag the singletons
+#[cfg(feature = "nan_boxing")]
+mod repr {
+    use std::fmt::Debug;
+
+    use crate::object::{IString, ObjRef};
+
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const STRING_BIT: u64 = 1 << 49;
+    const HANDLE_MASK: u64 = STRING_BIT - 1;
+
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    const FALSE: u64 = QNAN | TAG_FALSE;
+    const TRUE: u64 = QNAN | TAG_TRUE;
+
+    #[derive(Copy, Clone)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub const NIL: Value = Value(QNAN | TAG_NIL);
+
+        pub fn is_nil(&self) -> bool {
+            self.0 == Self::NIL.0
+        }
+
+        pub fn as_bool(&self) -> Option<bool> {
+            match self.0 {
+                TRUE => Some(true),
+                FALSE => Some(false),
+                _ => None,
+            }
+        }
+
+        pub fn as_num(&self) -> Option<f64> {
+            if self.0 & QNAN != QNAN {
+                Some(f64::from_bits(self.0))
+            } else {
+                None
+            }
+        }
+
+        pub fn as_str(&self) -> Option<IString> {
+            self.handle(STRING_BIT).map(IString)
+        }
+
+        pub fn as_obj(&self) -> Option<ObjRef> {
+            self.handle(0).map(ObjRef)
+        }
+
+        fn handle(&self, kind: u64) -> Option<usize> {
+            if self.0 & (QNAN | SIGN_BIT | STRING_BIT) == QNAN | SIGN_BIT | kind {
+                Some((self.0 & HANDLE_MASK) as usize)
+            } else {
+                None
+            }
+        }
+
+        fn from_handle(kind: u64, idx: usize) -> Self {
+            let idx = idx as u64;
+            assert!(idx <= HANDLE_MASK, "handle too large to box");
+            Value(QNAN | SIGN_BIT | kind | idx)
+        }
+
+        #[allow(clippy::should_implement_trait)]
+        pub fn eq(&self, other: &Value) -> bool {
+            match (self.as_num(), other.as_num()) {
+                (Some(a), Some(b)) => a == b,
+                _ => self.0 == other.0,
+            }
+        }
+    }
+
+    impl From<f64> for Value {
+        fn from(n: f64) -> Self {
+            Value(n.to_bits())
+        }
+    }
+
+    impl From<bool> for Value {
+        fn from(b: bool) -> Self {
+            Value(if b { TRUE } else { FALSE })
+        }
+    }
+
+    impl From<IString> for Value {
+        fn from(s: IString) -> Self {
+            Self::from_handle(STRING_BIT, s.0)
+        }
+    }
+
+    impl From<ObjRef> for Value {
+        fn from(o: ObjRef) -> Self {
+            Self::from_handle(0, o.0)
+        }
+    }
+
+    impl Debug for Value {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Value({self})")
+        }
     }
 }
 
-impl From<bool> for Value {
-    fn from(b: bool) -> Self {
-        Value::Bool(b)
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
     }
 }
 
 impl Default for Value {
     fn default() -> Self {
-        Self::Num(f64::default())
+        Self::from(f64::default())
     }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Nil => write!(f, "nil"),
-            Self::Bool(b) => write!(f, "{b}"),
-            Self::Num(n) => write!(f, "{n}"),
-            Self::String(s) => write!(f, "{s:?}"),
+        if let Some(b) = self.as_bool() {
+            write!(f, "{b}")
+        } else if let Some(n) = self.as_num() {
+            write!(f, "{n}")
+        } else if let Some(s) = self.as_str() {
+            write!(f, "{s:?}")
+        } else if let Some(o) = self.as_obj() {
+            write!(f, "{o:?}")
+        } else {
+            write!(f, "nil")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{IString, ObjRef};
+
+    #[test]
+    fn test_round_trip() {
+        for n in [0.0, -0.0, 1.5, -2e300, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(Value::from(n).as_num(), Some(n));
+        }
+        for nan in [f64::NAN, -f64::NAN] {
+            assert!(Value::from(nan).as_num().unwrap().is_nan());
         }
+
+        assert!(Value::NIL.is_nil());
+        assert_eq!(Value::NIL.as_num(), None);
+        assert_eq!(Value::from(true).as_bool(), Some(true));
+        assert_eq!(Value::from(false).as_bool(), Some(false));
+
+        let (istr, obj) = (IString(7), ObjRef(7));
+        assert_eq!(Value::from(istr).as_str(), Some(istr));
+        assert_eq!(Value::from(istr).as_obj(), None);
+        assert_eq!(Value::from(obj).as_obj(), Some(obj));
+        assert_eq!(Value::from(obj).as_str(), None);
+        assert_eq!(Value::from(obj).as_bool(), None);
+    }
+
+    #[test]
+    fn test_equality() {
+        assert!(Value::NIL.eq(&Value::NIL));
+        assert!(!Value::NIL.eq(&Value::from(false)));
+        assert!(!Value::from(0.1 + 0.2).eq(&Value::from(0.3)));
+        assert!(Value::from(-0.0).eq(&Value::from(0.0)));
+        assert!(!Value::from(f64::NAN).eq(&Value::from(f64::NAN)));
+        assert!(!Value::from(1.0).eq(&Value::from(true)));
+        assert!(!Value::from(IString(1)).eq(&Value::from(ObjRef(1))));
+        assert!(Value::NIL.is_falsey() && Value::from(false).is_falsey());
+        assert!(!Value::from(0.0).is_falsey());
     }
 }
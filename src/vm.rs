@@ -1,78 +1,517 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    io::{self, Write},
+    mem,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+};
 
 use crate::{
     chunk::{Chunk, OpCode, OpLen},
     compiler::Compiler,
-    object::{IString, StringInterner},
+    globals::Globals,
+    heap::Heap,
+    native,
+    object::{
+        BoundMethod, Class, Closure, IString, Instance, Map, Native, NativeFn, Obj, ObjRef, Upvalue,
+    },
+    optimizer,
     stack::Stack,
+    trace::{TraceEvent, Tracer},
     util::join_u8s,
     value::Value,
 };
 
+#[cfg(feature = "coverage")]
+use crate::coverage::Coverage;
+#[cfg(feature = "profile")]
+use crate::profile::Profile;
+
+const FRAMES_MAX: usize = 64;
+/// Default limit on values on the stack, enough for every frame to use
+/// a byte's worth of slots
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InterpretError {
+    Compile(Vec<Diagnostic>),
+    Runtime(Vec<Diagnostic>),
+    /// The instruction budget ran out. The script is suspended rather
+    /// than aborted and continues with Vm::resume once given more fuel
+    OutOfFuel,
+}
+
+impl InterpretError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Compile(..) => ErrorKind::Compile,
+            Self::Runtime(..) => ErrorKind::Runtime,
+            Self::OutOfFuel => ErrorKind::OutOfFuel,
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            Self::Compile(diagnostics) | Self::Runtime(diagnostics) => diagnostics,
+            Self::OutOfFuel => &[],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
     Compile,
     Runtime,
+    OutOfFuel,
+}
+
+/// Error found while compiling or running a script. Runtime errors have
+/// no column or lexeme since chunks only record lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub line: usize,
+    pub column: Option<usize>,
+    /// Source text of the offending token, empty at the end of input
+    pub lexeme: Option<String>,
+    /// Calls active when a runtime error occurred, innermost first
+    pub trace: Vec<TraceFrame>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ErrorKind::Compile => {
+                write!(f, "[line {}] Error", self.line)?;
+                match self.lexeme.as_deref() {
+                    Some("") => write!(f, " at end")?,
+                    Some(lexeme) => write!(f, " at {lexeme}")?,
+                    None => {}
+                }
+                write!(f, ": {}", self.message)
+            }
+            ErrorKind::Runtime | ErrorKind::OutOfFuel => {
+                write!(f, "{}", self.message)?;
+                for frame in &self.trace {
+                    write!(f, "\n{frame}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    pub line: usize,
+    /// Called function as shown in traces, such as "name()" or "script"
+    pub function: String,
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}] in {}", self.line, self.function)
+    }
 }
 
 pub type InterpretResult<T = ()> = Result<T, InterpretError>;
 
-pub struct Vm {
-    chunk: Chunk,
+/// Object an index expression is applied to, lists carry their length
+enum Indexable {
+    List(ObjRef, usize),
+    Map(ObjRef),
+}
+
+/// An ongoing function call. Slots is the stack index of the first
+/// slot the function can use, which holds the closure itself. The
+/// closure's function is kept alongside to avoid a lookup per instruction
+#[derive(Debug)]
+struct CallFrame {
+    closure: ObjRef,
+    function: ObjRef,
     ip: usize,
+    slots: usize,
+}
+
+/// Stops a running VM from another thread or a signal handler with an
+/// "Interrupted." runtime error
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed)
+    }
+
+    /// Clear the flag, returning whether it was set
+    fn take(&self) -> bool {
+        self.0.swap(false, atomic::Ordering::Relaxed)
+    }
+}
+
+pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: Stack<Value>,
-    interner: StringInterner,
-    globals: HashMap<String, Value>,
+    heap: Heap,
+    globals: Globals,
+    /// Upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<ObjRef>,
+    init_string: IString,
+    /// Receives printed values and debug output
+    output: Box<dyn Write>,
+    /// Receives compile and runtime errors
+    diagnostics: Box<dyn Write>,
+    /// Run the peephole optimizer over compiled code
+    optimize: bool,
+    /// Instructions left to run, unlimited when None
+    fuel: Option<u64>,
+    interrupt: InterruptHandle,
+    /// Most values the stack may hold
+    stack_limit: usize,
+    /// Receives every instruction run when set
+    tracer: Option<Box<dyn Tracer>>,
+    #[cfg(feature = "profile")]
+    profile: Option<Profile>,
+    #[cfg(feature = "coverage")]
+    coverage: Option<Coverage>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
-        Self {
-            chunk: Chunk::new(),
-            ip: 0,
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+
+        let mut vm = Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: Stack::new(),
-            interner: StringInterner::new(),
-            globals: HashMap::new(),
+            heap,
+            globals: Globals::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            output: Box::new(io::stdout()),
+            diagnostics: Box::new(io::stderr()),
+            optimize: false,
+            fuel: None,
+            interrupt: InterruptHandle::default(),
+            stack_limit: STACK_MAX,
+            tracer: None,
+            #[cfg(feature = "profile")]
+            profile: None,
+            #[cfg(feature = "coverage")]
+            coverage: None,
+        };
+
+        for &(name, arity, function) in native::DEFAULTS {
+            vm.define_native(name, arity, function);
+        }
+
+        vm
+    }
+
+    /// Send printed values and debug output to output instead of stdout
+    pub fn with_output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Send error messages to diagnostics instead of stderr
+    pub fn with_diagnostics(mut self, diagnostics: Box<dyn Write>) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    /// Fuse common instruction sequences in compiled code into
+    /// superinstructions before running it
+    pub fn with_optimizer(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Limit scripts to running roughly fuel instructions. Running out
+    /// suspends the script with InterpretError::OutOfFuel
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Instructions left to run, None if unlimited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Replace the remaining budget, None removes the limit
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Top up a limited budget, unlimited budgets stay unlimited
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = &mut self.fuel {
+            *left = left.saturating_add(fuel);
         }
     }
 
+    /// Raise "Stack overflow." once the stack holds more than values
+    pub fn with_stack_limit(mut self, values: usize) -> Self {
+        self.stack_limit = values;
+        self
+    }
+
+    /// Raise "Out of memory." when objects and interned strings need more
+    /// than bytes even after collecting garbage. Unlimited by default
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.heap.set_limit(bytes);
+        self
+    }
+
+    /// Pass every instruction to tracer before running it, None stops
+    /// tracing
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    /// Count the opcodes and lines executed and time each line
+    #[cfg(feature = "profile")]
+    pub fn with_profiler(mut self) -> Self {
+        self.profile = Some(Profile::new());
+        self
+    }
+
+    /// Profile of everything run so far if profiling
+    #[cfg(feature = "profile")]
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Record which lines of the scripts run have executed
+    #[cfg(feature = "coverage")]
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = Some(Coverage::new());
+        self
+    }
+
+    /// Coverage of everything run so far if recording it
+    #[cfg(feature = "coverage")]
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Handle for interrupting the scripts this VM runs
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Install host function as a global, replacing any existing global
+    /// with the same name
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Native {
+            name: self.heap.intern(name),
+            arity,
+            function,
+        };
+        let native = self.heap.alloc(Obj::Native(native));
+        let slot = self.globals.resolve(name);
+        self.globals.define(slot, Value::from(native));
+    }
+
+    /// Compile and run src, writing any errors to the diagnostics sink
+    /// before returning them
     pub fn interpret(&mut self, src: &str) -> InterpretResult {
-        let compiler = Compiler::new(src, &mut self.interner);
+        let result = self.compile_and_run(src);
+        self.finish(&result);
+        result
+    }
+
+    /// Continue the script suspended by running out of fuel, doing nothing
+    /// if there isn't one. Errors are reported as for interpret
+    pub fn resume(&mut self) -> InterpretResult {
+        if self.frames.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.run();
+        self.finish(&result);
+        result
+    }
+
+    /// Compile src and stop before its first instruction so it can be run
+    /// with step. Errors are reported as for interpret
+    pub fn load(&mut self, src: &str) -> InterpretResult {
+        let result = self.compile_and_call(src);
+        self.finish(&result);
+        result
+    }
+
+    /// Run the next instruction of the loaded or suspended script,
+    /// returning whether there is more to run. Errors are reported as for
+    /// interpret
+    pub fn step(&mut self) -> InterpretResult<bool> {
+        if self.frames.is_empty() {
+            return Ok(false);
+        }
+
+        let result = self.run_instruction();
+        if !matches!(result, Ok(true)) {
+            self.finish(&result);
+        }
+        result
+    }
 
-        let chunk = compiler.compile()?;
-        self.chunk = chunk;
-        self.ip = 0;
+    /// Number of calls in progress, zero when no script is running
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
 
+    /// Line of the next instruction to run
+    pub fn line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        Some(self.heap.function(frame.function).chunk.get_line(frame.ip))
+    }
+
+    /// Line of the statement the next instruction begins, None in the
+    /// middle of a statement
+    pub fn statement_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        self.heap
+            .function(frame.function)
+            .chunk
+            .statement_at(frame.ip)
+    }
+
+    /// Values on the stack, oldest first
+    pub fn stack(&self) -> &[Value] {
+        self.stack.as_slice()
+    }
+
+    /// Name and value of each local in scope in the innermost call
+    pub fn locals(&self) -> Vec<(&str, Value)> {
+        let Some(frame) = self.frames.last() else {
+            return Vec::new();
+        };
+
+        // Locals being discarded at the end of a scope may already be off
+        // the stack
+        let chunk = &self.heap.function(frame.function).chunk;
+        chunk
+            .locals_at(frame.ip)
+            .into_iter()
+            .filter_map(|l| {
+                let value = self.stack.get(frame.slots + l.slot)?;
+                Some((l.name.as_str(), *value))
+            })
+            .collect()
+    }
+
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    /// Value as print would show it
+    pub fn format_value(&self, value: Value) -> String {
+        self.heap.format_value(value)
+    }
+
+    /// Stop timing and write any errors to the diagnostics sink once
+    /// the VM stops running
+    fn finish<T>(&mut self, result: &InterpretResult<T>) {
+        #[cfg(feature = "profile")]
+        if let Some(profile) = &mut self.profile {
+            profile.pause();
+        }
+
+        if let Err(e) = result {
+            for diagnostic in e.diagnostics() {
+                // The error is returned anyway so a failed write is ignored
+                let _ = writeln!(self.diagnostics, "{diagnostic}");
+            }
+        }
+    }
+
+    fn compile_and_run(&mut self, src: &str) -> InterpretResult {
+        self.compile_and_call(src)?;
         self.run()
     }
 
-    fn read_byte(&mut self) -> Option<OpCode> {
-        let instruction = self.chunk.get_op(self.ip);
-        self.ip += 1;
+    /// Compile src and call it, ready to run its first instruction
+    fn compile_and_call(&mut self, src: &str) -> InterpretResult {
+        let compiler = Compiler::new(src, &mut self.heap, &mut self.globals);
+        #[cfg(feature = "debug_print_code")]
+        let compiler = compiler.with_output(&mut *self.output);
+        let mut function = compiler.compile()?;
+        if self.optimize {
+            optimizer::optimize(&mut function.chunk, &mut self.heap);
+        }
+
+        // New code replaces any script left suspended, and any interrupt
+        // was meant for the code that was running before
+        self.reset_stack();
+        self.interrupt.take();
+
+        let function = self.heap.alloc(Obj::Function(function));
+        #[cfg(feature = "coverage")]
+        if let Some(coverage) = &mut self.coverage {
+            coverage.add_function(function, &self.heap);
+        }
+        let closure = self.heap.alloc(Obj::Closure(Closure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(closure);
+        self.call(closure, 0)
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("a call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.heap.function(self.frame().function).chunk
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let frame = self.frames.last_mut()?;
+        let byte = self.heap.function(frame.function).chunk.get_byte(frame.ip);
+        frame.ip += 1;
+
+        byte
+    }
 
-        instruction
+    fn read_op(&mut self) -> Option<OpCode> {
+        self.read_byte().and_then(OpCode::from_byte)
+    }
+
+    fn jump(&mut self, offset: isize) {
+        let frame = self.frames.last_mut().expect("a call frame");
+        frame.ip = frame.ip.wrapping_add_signed(offset)
     }
 
     fn read_short(&mut self) -> Option<u16> {
-        let b1 = self.read_byte().and_then(|o| o.as_byte())?;
-        let b2 = self.read_byte().and_then(|o| o.as_byte())?;
+        let b1 = self.read_byte()?;
+        let b2 = self.read_byte()?;
         let idx = join_u8s(b1, b2);
         Some(idx)
     }
 
     fn read_idx<L: Into<OpLen>>(&mut self, len: L) -> Option<usize> {
         match len.into() {
-            OpLen::Short => self
-                .read_byte()
-                .and_then(|o| o.as_byte())
-                .map(|b| b as usize),
+            OpLen::Short => self.read_byte().map(|b| b as usize),
             OpLen::Long => self.read_short().map(|b| b as usize),
         }
     }
 
     fn read_constant<L: Into<OpLen>>(&mut self, len: L) -> Option<&Value> {
         let idx = self.read_idx(len)?;
-        self.chunk.get_constant(idx)
+        self.chunk().get_constant(idx)
     }
 
     /// This does not convert the IString with the interner because IString is copy
@@ -82,143 +521,404 @@ impl Vm {
     }
 
     fn run(&mut self) -> InterpretResult {
-        loop {
-            #[cfg(feature = "debug_trace_execution")]
-            {
-                print!("\t\t");
-                for val in &self.stack {
-                    print!("[ {val} ]")
-                }
-                println!();
-                self.chunk.disassemble_instruction(self.ip);
+        while self.run_instruction()? {}
+        Ok(())
+    }
+
+    /// Run the next instruction, returning false once the script has
+    /// returned
+    #[inline(always)]
+    fn run_instruction(&mut self) -> InterpretResult<bool> {
+        // Between instructions every live value is reachable from the
+        // roots so this is the only place collection happens
+        if self.heap.should_collect() {
+            self.collect_garbage();
+            if self.heap.over_limit() {
+                return Err(self.runtime_error("Out of memory."));
             }
+        }
 
-            match self.read_byte().ok_or(InterpretError::Compile)? {
-                code @ (OpCode::Constant | OpCode::ConstantLong) => {
-                    let constant = *self.read_constant(code).ok_or(InterpretError::Compile)?;
-                    self.stack.push(constant);
-                }
-                OpCode::Nil => self.stack.push(Value::Nil),
-                OpCode::True => self.stack.push(Value::Bool(true)),
-                OpCode::False => self.stack.push(Value::Bool(false)),
-                OpCode::Pop => {
-                    self.stack.pop();
-                }
-                code @ (OpCode::GetLocal | OpCode::GetLocalLong) => {
-                    let slot = self.read_idx(code).expect("a slot idx");
-                    let slot_val = *self.stack.get(slot).expect("invalid stack idx");
-                    self.stack.push(slot_val);
-                }
-                code @ (OpCode::SetLocal | OpCode::SetLocalLong) => {
-                    let slot = self.read_idx(code).expect("a slot idx");
-                    let new_val = *self.stack.peek(0).expect("invalid stack idx");
-                    self.stack
-                        .set(slot, new_val)
-                        .expect("failed to update slot");
+        // Calls aren't the only way to grow the stack, a function can have
+        // thousands of locals
+        if self.stack.len() > self.stack_limit {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        #[cfg(feature = "debug_trace_execution")]
+        self.trace_instruction().map_err(|e| self.output_error(e))?;
+
+        self.call_tracer()
+            .map_err(|e| self.runtime_error(format!("Failed to write trace, {e}")))?;
+
+        #[cfg(feature = "profile")]
+        self.profile_instruction();
+
+        #[cfg(feature = "coverage")]
+        self.cover_instruction();
+
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_sub(1);
+        }
+
+        match self.read_op().expect("an instruction") {
+            code @ (OpCode::Constant | OpCode::ConstantLong) => {
+                let constant = *self.read_constant(code).expect("a constant");
+                self.stack.push(constant);
+            }
+            OpCode::Nil => self.stack.push(Value::NIL),
+            OpCode::True => self.stack.push(true),
+            OpCode::False => self.stack.push(false),
+            OpCode::Pop => {
+                self.stack.pop();
+            }
+            code @ (OpCode::GetLocal | OpCode::GetLocalLong) => {
+                let slot = self.frame().slots + self.read_idx(code).expect("a slot idx");
+                let slot_val = *self.stack.get(slot).expect("invalid stack idx");
+                self.stack.push(slot_val);
+            }
+            code @ (OpCode::SetLocal | OpCode::SetLocalLong) => {
+                let slot = self.frame().slots + self.read_idx(code).expect("a slot idx");
+                let new_val = *self.stack.peek(0).expect("invalid stack idx");
+                self.stack
+                    .set(slot, new_val)
+                    .expect("failed to update slot");
+            }
+            code @ (OpCode::GetGlobal | OpCode::GetGlobalLong) => {
+                let slot = self.read_idx(code).expect("a global slot");
+                if let Some(value) = self.globals.get(slot) {
+                    self.stack.push(value)
+                } else {
+                    return Err(self.undefined_variable(slot));
                 }
-                code @ (OpCode::GetGlobal | OpCode::GetGlobalLong) => {
-                    let iname = self.read_string(code).expect("expected string");
-                    let name = self.interner.get(iname);
+            }
+            code @ (OpCode::DefineGlobal | OpCode::DefineGlobalLong) => {
+                let slot = self.read_idx(code).expect("a global slot");
+                let value = *self.stack.peek(0).unwrap();
 
-                    if let Some(&value) = self.globals.get(name) {
-                        self.stack.push(value)
-                    } else {
-                        let name = name.to_owned();
-                        self.runtime_error(format!("Undefined variable '{name}'"));
-                        return Err(InterpretError::Runtime);
-                    }
+                self.globals.define(slot, value);
+                self.stack.pop();
+            }
+            code @ (OpCode::SetGlobal | OpCode::SetGlobalLong) => {
+                let slot = self.read_idx(code).expect("a global slot");
+                let value = *self.stack.peek(0).unwrap();
+                if !self.globals.set(slot, value) {
+                    return Err(self.undefined_variable(slot));
+                }
+            }
+            OpCode::GetUpvalue => {
+                let slot = self.read_idx(OpLen::Short).expect("an upvalue idx");
+                let upvalue = self.upvalue(slot);
+                let value = match *self.heap.get(upvalue).as_upvalue().unwrap() {
+                    Upvalue::Open(slot) => *self.stack.get(slot).expect("invalid stack idx"),
+                    Upvalue::Closed(value) => value,
+                };
+                self.stack.push(value);
+            }
+            OpCode::SetUpvalue => {
+                let slot = self.read_idx(OpLen::Short).expect("an upvalue idx");
+                let upvalue = self.upvalue(slot);
+                let new_val = *self.stack.peek(0).expect("invalid stack idx");
+                match self.heap.get_mut(upvalue).as_upvalue_mut().unwrap() {
+                    Upvalue::Open(slot) => self
+                        .stack
+                        .set(*slot, new_val)
+                        .expect("failed to update slot"),
+                    Upvalue::Closed(value) => *value = new_val,
                 }
-                code @ (OpCode::DefineGlobal | OpCode::DefineGlobalLong) => {
-                    let iname = self.read_string(code).expect("expected string");
-                    let name = self.interner.get(iname).to_owned();
-                    let value = *self.stack.peek(0).unwrap();
+            }
+            code @ (OpCode::GetProperty | OpCode::GetPropertyLong) => {
+                let name = self.read_string(code).expect("expected string");
+                let Some(instance) = self.peek_instance(0) else {
+                    return Err(self.runtime_error("Only instances have properties."));
+                };
 
-                    self.globals.insert(name, value);
+                // Fields shadow methods
+                let instance = self.heap.instance(instance);
+                if let Some(&value) = instance.fields.get(&name) {
                     self.stack.pop();
+                    self.stack.push(value);
+                } else {
+                    self.bind_method(instance.class, name)?;
                 }
-                code @ (OpCode::SetGlobal | OpCode::SetGlobalLong) => {
-                    let iname = self.read_string(code).expect("expected string");
-                    let name = self.interner.get(iname);
+            }
+            code @ (OpCode::SetProperty | OpCode::SetPropertyLong) => {
+                let name = self.read_string(code).expect("expected string");
+                let Some(instance) = self.peek_instance(1) else {
+                    return Err(self.runtime_error("Only instances have fields."));
+                };
 
-                    if let Some(val) = self.globals.get_mut(name) {
-                        let new_val = self.stack.peek(0).unwrap();
-                        *val = *new_val
-                    } else {
-                        let name = name.to_owned();
-                        self.runtime_error(format!("Undefined variable '{name}'"));
-                        return Err(InterpretError::Runtime);
-                    }
-                }
-                OpCode::Equal => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(a.eq(&b)));
-                }
-                OpCode::Greater => self.binary_op(|a, b| a > b)?,
-                OpCode::Less => self.binary_op(|a, b| a < b)?,
-                OpCode::Add => match (self.stack.peek(0), self.stack.peek(1)) {
-                    (Some(Value::String(..)), Some(Value::String(..))) => {
-                        let b_intered = self.stack.pop().unwrap().as_str().unwrap();
-                        let b = self.interner.get(b_intered);
-
-                        let a_intered = self.stack.pop().unwrap().as_str().unwrap();
-                        let a = self.interner.get(a_intered);
-
-                        let concated = format!("{a}{b}");
-                        let res = self.interner.intern(concated);
-                        self.stack.push(Value::String(res))
+                let value = self.stack.pop().unwrap();
+                let instance = self.heap.get_mut(instance).as_instance_mut().unwrap();
+                instance.fields.insert(name, value);
+                self.heap.grow(mem::size_of::<(IString, Value)>());
+
+                self.stack.pop();
+                self.stack.push(value);
+            }
+            code @ (OpCode::GetSuper | OpCode::GetSuperLong) => {
+                let name = self.read_string(code).expect("expected string");
+                let superclass = self.stack.pop().and_then(|c| c.as_obj()).expect("a class");
+                self.bind_method(superclass, name)?;
+            }
+            OpCode::GetIndex => {
+                let index = self.stack.pop().unwrap();
+                let target = self.stack.pop().unwrap();
+
+                let value = match self.indexable(target)? {
+                    Indexable::List(list, len) => {
+                        let idx = self.list_index(index, len)?;
+                        self.heap.get(list).as_list().unwrap()[idx]
                     }
-                    (Some(Value::Num(..)), Some(Value::Num(..))) => {
-                        let b = self.stack.pop().unwrap().as_num().unwrap();
-                        let a = self.stack.pop().unwrap().as_num().unwrap();
-                        self.stack.push(Value::Num(a + b));
+                    Indexable::Map(map) => {
+                        self.map_key(index)?;
+                        match self.heap.get(map).as_map().unwrap().get(index) {
+                            Some(value) => value,
+                            None => {
+                                let key = self.heap.format_value(index);
+                                return Err(self.runtime_error(format!("Undefined key {key}.")));
+                            }
+                        }
                     }
-                    _ => {
-                        self.runtime_error("Operands must be two numbers or two strings.");
-                        return Err(InterpretError::Runtime);
+                };
+                self.stack.push(value);
+            }
+            OpCode::SetIndex => {
+                let value = self.stack.pop().unwrap();
+                let index = self.stack.pop().unwrap();
+                let target = self.stack.pop().unwrap();
+
+                match self.indexable(target)? {
+                    Indexable::List(list, len) => {
+                        let idx = self.list_index(index, len)?;
+                        self.heap.get_mut(list).as_list_mut().unwrap()[idx] = value;
                     }
-                },
-                OpCode::Subtract => self.binary_op(|a, b| a - b)?,
-                OpCode::Multiply => self.binary_op(|a, b| a * b)?,
-                OpCode::Divide => self.binary_op(|a, b| a / b)?,
-                OpCode::Not => {
-                    let val = self.stack.pop().unwrap().is_falsey();
-                    self.stack.push(Value::Bool(val))
-                }
-                OpCode::Negate => {
-                    if let Some(Value::Num(..)) = self.stack.peek(0) {
-                        let constant = self.stack.pop().unwrap().as_num().unwrap();
-                        self.stack.push(Value::Num(-constant))
-                    } else {
-                        self.runtime_error("Operand must be a number.");
-                        return Err(InterpretError::Runtime);
+                    Indexable::Map(map) => {
+                        self.map_key(index)?;
+                        let map = self.heap.get_mut(map).as_map_mut().unwrap();
+                        map.insert(index, value);
+                        self.heap.grow(mem::size_of::<(Value, Value)>());
                     }
                 }
-                OpCode::Print => {
-                    let value = self.stack.pop().unwrap();
-                    self.print_val(value);
+                self.stack.push(value);
+            }
+            OpCode::Equal => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                self.stack.push(a.eq(&b));
+            }
+            OpCode::Greater => self.binary_op(|a, b| a > b)?,
+            OpCode::Less => self.binary_op(|a, b| a < b)?,
+            OpCode::Add => self.add()?,
+            OpCode::Subtract => self.binary_op(|a, b| a - b)?,
+            OpCode::Multiply => self.binary_op(|a, b| a * b)?,
+            OpCode::Divide => self.binary_op(|a, b| a / b)?,
+            OpCode::Not => {
+                let val = self.stack.pop().unwrap().is_falsey();
+                self.stack.push(val)
+            }
+            OpCode::Negate => {
+                if let Some(constant) = self.stack.peek(0).and_then(Value::as_num) {
+                    self.stack.pop();
+                    self.stack.push(-constant)
+                } else {
+                    return Err(self.runtime_error("Operand must be a number."));
                 }
-                OpCode::Jump => {
-                    let offset = self.read_short().expect("a short to jump to");
-                    self.ip += offset as usize
+            }
+            OpCode::Stringify => {
+                let value = self.stack.pop().unwrap();
+                let str = match value.as_str() {
+                    Some(..) => value,
+                    None => Value::from(self.heap.intern(self.heap.to_string(value))),
+                };
+                self.stack.push(str);
+            }
+            OpCode::NotEqual => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                self.stack.push(!a.eq(&b));
+            }
+            // Written as negations since they must match 'Less Not'
+            // and 'Greater Not' when a number is NaN
+            OpCode::GreaterEqual => {
+                self.binary_op(|a, b| a.partial_cmp(&b) != Some(Ordering::Less))?
+            }
+            OpCode::LessEqual => {
+                self.binary_op(|a, b| a.partial_cmp(&b) != Some(Ordering::Greater))?
+            }
+            OpCode::SetLocalPop => {
+                let slot = self.frame().slots + self.read_idx(OpLen::Short).expect("a slot idx");
+                let new_val = self.stack.pop().expect("a value to assign");
+                self.stack
+                    .set(slot, new_val)
+                    .expect("failed to update slot");
+            }
+            OpCode::GetLocalAddConstant => {
+                let slot = self.frame().slots + self.read_idx(OpLen::Short).expect("a slot idx");
+                let slot_val = *self.stack.get(slot).expect("invalid stack idx");
+                let constant = *self.read_constant(OpLen::Short).expect("a constant");
+                self.stack.push(slot_val);
+                self.stack.push(constant);
+                self.add()?;
+            }
+            OpCode::Print => {
+                let value = self.stack.pop().unwrap();
+                self.print_val(value)?;
+            }
+            OpCode::Jump => {
+                let offset = self.read_short().expect("a short to jump to");
+                self.jump(offset as isize)
+            }
+            OpCode::JumpIfFalse => {
+                let offset = self.read_short().expect("a short to jump to");
+                let cond = self.stack.peek(0).expect("a test condition");
+                if cond.is_falsey() {
+                    self.jump(offset as isize)
                 }
-                OpCode::JumpIfFalse => {
-                    let offset = self.read_short().expect("a short to jump to");
-                    let cond = self.stack.peek(0).expect("a test condition");
-                    if cond.is_falsey() {
-                        self.ip += offset as usize
+            }
+            OpCode::Loop => {
+                let offset = self.read_short().expect("a short to jump to");
+                self.jump(-(offset as isize));
+                self.check_limits()?;
+            }
+            OpCode::Call => {
+                let arg_count = self.read_idx(OpLen::Short).expect("an argument count");
+                let callee = *self.stack.peek(arg_count).expect("a callee");
+                self.call_value(callee, arg_count)?;
+                self.check_limits()?;
+            }
+            code @ (OpCode::Invoke | OpCode::InvokeLong) => {
+                let name = self.read_string(code).expect("expected string");
+                let arg_count = self.read_idx(OpLen::Short).expect("an argument count");
+                self.invoke(name, arg_count)?;
+                self.check_limits()?;
+            }
+            code @ (OpCode::SuperInvoke | OpCode::SuperInvokeLong) => {
+                let name = self.read_string(code).expect("expected string");
+                let arg_count = self.read_idx(OpLen::Short).expect("an argument count");
+                let superclass = self.stack.pop().and_then(|c| c.as_obj()).expect("a class");
+                self.invoke_from_class(superclass, name, arg_count)?;
+                self.check_limits()?;
+            }
+            code @ (OpCode::Closure | OpCode::ClosureLong) => {
+                let function = self
+                    .read_constant(code)
+                    .and_then(|c| c.as_obj())
+                    .expect("a function constant");
+                let count = self.read_idx(OpLen::Short).expect("an upvalue count");
+
+                let mut upvalues = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let is_local = self.read_idx(OpLen::Short).expect("an is_local flag");
+                    let index = self.read_idx(OpLen::Long).expect("an upvalue idx");
+                    if is_local == 1 {
+                        upvalues.push(self.capture_upvalue(self.frame().slots + index));
+                    } else {
+                        upvalues.push(self.upvalue(index));
                     }
                 }
-                OpCode::Loop => {
-                    let offset = self.read_short().expect("a short to jump to");
-                    self.ip -= offset as usize
+
+                let closure = self
+                    .heap
+                    .alloc(Obj::Closure(Closure { function, upvalues }));
+                self.stack.push(closure);
+            }
+            OpCode::CloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.stack.pop();
+            }
+            OpCode::Return => {
+                let result = self.stack.pop().expect("a return value");
+                let frame = self.frames.pop().expect("a call frame");
+                self.close_upvalues(frame.slots);
+                if self.frames.is_empty() {
+                    // Pop the script function
+                    self.stack.pop();
+                    return Ok(false);
                 }
-                OpCode::Return => {
-                    return Ok(());
+
+                self.stack.truncate(frame.slots);
+                self.stack.push(result);
+            }
+            code @ (OpCode::Class | OpCode::ClassLong) => {
+                let name = self.read_string(code).expect("expected string");
+                let class = self.heap.alloc(Obj::Class(Class::new(name)));
+                self.stack.push(class);
+            }
+            OpCode::BuildList => {
+                let count = self.read_idx(OpLen::Short).expect("an item count");
+                let items = self.stack.top(count).to_vec();
+                let list = self.heap.alloc(Obj::List(items));
+
+                self.stack.truncate(self.stack.len() - count);
+                self.stack.push(list);
+            }
+            OpCode::BuildMap => {
+                let count = self.read_idx(OpLen::Short).expect("an entry count");
+                let entries = self.stack.top(count * 2).to_vec();
+
+                let mut map = Map::new();
+                for pair in entries.chunks(2) {
+                    self.map_key(pair[0])?;
+                    map.insert(pair[0], pair[1]);
                 }
-                OpCode::Byte(b) => unimplemented!("unimplemented opcode {b}"),
+                let map = self.heap.alloc(Obj::Map(map));
+
+                self.stack.truncate(self.stack.len() - count * 2);
+                self.stack.push(map);
+            }
+            OpCode::Inherit => {
+                let superclass = self
+                    .stack
+                    .peek(1)
+                    .and_then(|c| c.as_obj())
+                    .filter(|&c| self.heap.get(c).as_class().is_some());
+                let Some(superclass) = superclass else {
+                    return Err(self.runtime_error("Superclass must be a class."));
+                };
+
+                // Copy methods down so lookups never walk the hierarchy,
+                // subclass methods are added afterwards and override these
+                let methods = self.heap.class(superclass).methods.clone();
+                let subclass = self.stack.pop().and_then(|c| c.as_obj()).expect("a class");
+                let subclass = self.heap.get_mut(subclass).as_class_mut().expect("a class");
+                subclass.methods.extend(methods);
+            }
+            code @ (OpCode::Method | OpCode::MethodLong) => {
+                let name = self.read_string(code).expect("expected string");
+                let method = self.stack.pop().and_then(|m| m.as_obj()).expect("a method");
+                let class = self
+                    .stack
+                    .peek(0)
+                    .and_then(|c| c.as_obj())
+                    .expect("a class");
+
+                let class = self.heap.get_mut(class).as_class_mut().expect("a class");
+                class.methods.insert(name, method);
             }
         }
+
+        Ok(true)
+    }
+
+    /// Concatenate strings or add numbers
+    fn add(&mut self) -> InterpretResult {
+        if let Some((a, b)) = self.peek_operands(Value::as_str) {
+            // Checked up front since the result could be huge
+            let len = self.heap.get_str(a).len() + self.heap.get_str(b).len();
+            self.reserve_memory(len)?;
+
+            let concated = format!("{}{}", self.heap.get_str(a), self.heap.get_str(b));
+            let res = self.heap.intern(concated);
+            self.stack.truncate(self.stack.len() - 2);
+            self.stack.push(res);
+        } else if let Some((a, b)) = self.peek_operands(Value::as_num) {
+            self.stack.truncate(self.stack.len() - 2);
+            self.stack.push(a + b);
+        } else {
+            return Err(self.runtime_error("Operands must be two numbers or two strings."));
+        }
+
+        Ok(())
     }
 
     fn binary_op<F, V>(&mut self, f: F) -> InterpretResult
@@ -226,52 +926,1311 @@ impl Vm {
         F: Fn(f64, f64) -> V,
         V: Into<Value>,
     {
-        if let (Some(Value::Num(..)), Some(Value::Num(..))) =
-            (self.stack.peek(0), self.stack.peek(1))
-        {
-            let b = self.stack.pop().unwrap().as_num().unwrap();
-            let a = self.stack.pop().unwrap().as_num().unwrap();
+        if let Some((a, b)) = self.peek_operands(Value::as_num) {
+            self.stack.truncate(self.stack.len() - 2);
             self.stack.push(f(a, b));
             Ok(())
         } else {
-            self.runtime_error("Operands must be numbers.");
-            return Err(InterpretError::Runtime);
+            Err(self.runtime_error("Operands must be numbers."))
+        }
+    }
+
+    /// Both operands of a binary operator if as_type accepts them, left
+    /// operand first
+    fn peek_operands<T>(&self, as_type: fn(&Value) -> Option<T>) -> Option<(T, T)> {
+        let b = as_type(self.stack.peek(0)?)?;
+        let a = as_type(self.stack.peek(1)?)?;
+        Some((a, b))
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> InterpretResult {
+        let callee = match callee.as_obj() {
+            Some(obj) => obj,
+            None => {
+                return Err(self.runtime_error("Can only call functions and classes."));
+            }
+        };
+
+        // Callee's slot is replaced by the receiver for methods and classes
+        let slot = self.stack.len() - arg_count - 1;
+        match self.heap.get(callee) {
+            Obj::Closure(..) => self.call(callee, arg_count),
+            Obj::Native(native) => {
+                let (arity, function) = (native.arity, native.function);
+                if arg_count != arity {
+                    return Err(self.runtime_error(format!(
+                        "Expected {arity} arguments but got {arg_count}."
+                    )));
+                }
+
+                match function(&mut self.heap, self.stack.top(arg_count)) {
+                    Ok(result) => {
+                        self.stack.truncate(slot);
+                        self.stack.push(result);
+                        Ok(())
+                    }
+                    Err(msg) => Err(self.runtime_error(msg)),
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                let method = bound.method;
+                self.stack.set(slot, bound.receiver);
+                self.call(method, arg_count)
+            }
+            Obj::Class(class) => {
+                let init = class.methods.get(&self.init_string).copied();
+                let instance = self.heap.alloc(Obj::Instance(Instance::new(callee)));
+                self.stack.set(slot, instance);
+
+                if let Some(init) = init {
+                    self.call(init, arg_count)
+                } else if arg_count != 0 {
+                    Err(self.runtime_error(format!("Expected 0 arguments but got {arg_count}.")))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes.")),
+        }
+    }
+
+    /// Call method on receiver under the arguments, skipping creation of
+    /// a bound method unless a field holds the callee
+    fn invoke(&mut self, name: IString, arg_count: usize) -> InterpretResult {
+        let Some(receiver) = self.peek_instance(arg_count) else {
+            return Err(self.runtime_error("Only instances have methods."));
+        };
+
+        let instance = self.heap.instance(receiver);
+        if let Some(&field) = instance.fields.get(&name) {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack.set(slot, field);
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: IString,
+        arg_count: usize,
+    ) -> InterpretResult {
+        match self.heap.class(class).methods.get(&name) {
+            Some(&method) => self.call(method, arg_count),
+            None => {
+                let name = self.heap.get_str(name).to_owned();
+                Err(self.runtime_error(format!("Undefined property '{name}'.")))
+            }
+        }
+    }
+
+    /// Replace instance on top of stack with its method bound to it
+    fn bind_method(&mut self, class: ObjRef, name: IString) -> InterpretResult {
+        let Some(&method) = self.heap.class(class).methods.get(&name) else {
+            let name = self.heap.get_str(name).to_owned();
+            return Err(self.runtime_error(format!("Undefined property '{name}'.")));
+        };
+
+        let receiver = self.stack.pop().unwrap();
+        let bound = self
+            .heap
+            .alloc(Obj::BoundMethod(BoundMethod { receiver, method }));
+        self.stack.push(bound);
+        Ok(())
+    }
+
+    /// Collection value refers to, erroring if it isn't one
+    fn indexable(&mut self, value: Value) -> InterpretResult<Indexable> {
+        if let Some(obj) = value.as_obj() {
+            match self.heap.get(obj) {
+                Obj::List(list) => return Ok(Indexable::List(obj, list.len())),
+                Obj::Map(..) => return Ok(Indexable::Map(obj)),
+                _ => {}
+            }
+        }
+
+        Err(self.runtime_error("Only lists and maps can be indexed."))
+    }
+
+    fn map_key(&mut self, key: Value) -> InterpretResult {
+        if Map::is_valid_key(key) {
+            Ok(())
+        } else {
+            Err(self.runtime_error(
+                "Map keys must be strings, numbers other than NaN, booleans or nil.",
+            ))
+        }
+    }
+
+    /// Convert index value to a position in a list of len items
+    fn list_index(&mut self, index: Value, len: usize) -> InterpretResult<usize> {
+        let msg = match index.as_num() {
+            None => "List index must be a number.",
+            Some(n) if n.fract() != 0.0 => "List index must be an integer.",
+            Some(n) if n < 0.0 => "List index can't be negative.",
+            Some(n) if n as usize >= len => "List index out of range.",
+            Some(n) => return Ok(n as usize),
+        };
+
+        Err(self.runtime_error(msg))
+    }
+
+    /// Instance distance down the stack, if the value there is one
+    fn peek_instance(&self, distance: usize) -> Option<ObjRef> {
+        let obj = self.stack.peek(distance)?.as_obj()?;
+        self.heap.get(obj).as_instance().map(|_| obj)
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> InterpretResult {
+        let function = self.heap.closure(closure).function;
+        let arity = self.heap.function(function).arity;
+        if arg_count != arity {
+            return Err(
+                self.runtime_error(format!("Expected {arity} arguments but got {arg_count}."))
+            );
+        }
+
+        if self.frames.len() == FRAMES_MAX || self.stack.len() > self.stack_limit {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Mark roots of the object graph and reclaim everything else
+    fn collect_garbage(&mut self) {
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
         }
+
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+
+        for (_, value) in self.globals.iter() {
+            self.heap.mark_value(value);
+        }
+
+        #[cfg(feature = "coverage")]
+        if let Some(coverage) = &self.coverage {
+            for &function in coverage.functions() {
+                self.heap.mark_object(function);
+            }
+        }
+
+        self.heap.mark_string(self.init_string);
+        self.heap.collect();
     }
 
-    fn runtime_error<D: Display>(&mut self, msg: D) {
-        println!("{msg}");
+    /// Upvalue at idx of the current frame's closure
+    fn upvalue(&self, idx: usize) -> ObjRef {
+        self.heap.closure(self.frame().closure).upvalues[idx]
+    }
+
+    /// Reuse open upvalue for slot if one exists so closures capturing the
+    /// same variable share it
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut insert_at = self.open_upvalues.len();
+        for (idx, &upvalue) in self.open_upvalues.iter().enumerate() {
+            match *self.heap.get(upvalue).as_upvalue().unwrap() {
+                Upvalue::Open(open) if open == slot => return upvalue,
+                Upvalue::Open(open) if open > slot => {
+                    insert_at = idx;
+                    break;
+                }
+                _ => {}
+            }
+        }
 
-        let line = self.chunk.get_line(self.ip - 1);
-        eprintln!("[line {line}] in script");
-        self.stack.reset()
+        let upvalue = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
     }
 
-    fn print_val(&self, val: Value) {
-        if let Value::String(istr) = val {
-            let str = self.interner.get(istr);
-            println!("\"{str}\"")
+    /// Move every variable at or above stack slot last into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let upvalue = self.heap.get_mut(upvalue).as_upvalue_mut().unwrap();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= last => {
+                    *upvalue = Upvalue::Closed(*self.stack.get(slot).expect("invalid stack idx"));
+                    self.open_upvalues.pop();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Interrupts and fuel are only checked after loops and calls, the
+    /// only ways code runs again, so straight line code may overrun the
+    /// budget a little. The instruction has finished so a script out of
+    /// fuel can be resumed
+    fn check_limits(&mut self) -> InterpretResult {
+        if self.interrupt.take() {
+            return Err(self.runtime_error("Interrupted."));
+        }
+
+        match self.fuel {
+            Some(0) => Err(InterpretError::OutOfFuel),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make sure bytes more can be allocated within the memory limit,
+    /// collecting garbage first if needed. Values in use must be reachable
+    /// from the roots
+    fn reserve_memory(&mut self, bytes: usize) -> InterpretResult {
+        if self.heap.fits(bytes) {
+            return Ok(());
+        }
+
+        self.collect_garbage();
+        if self.heap.fits(bytes) {
+            Ok(())
         } else {
-            println!("{val}")
+            Err(self.runtime_error("Out of memory."))
+        }
+    }
+
+    fn undefined_variable(&mut self, slot: usize) -> InterpretError {
+        let name = self.globals.name(slot).to_owned();
+        self.runtime_error(format!("Undefined variable '{name}'"))
+    }
+
+    /// Build the error for msg with a trace of the active calls, then
+    /// reset the stack
+    fn runtime_error<D: Display>(&mut self, msg: D) -> InterpretError {
+        let trace: Vec<_> = self
+            .frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(depth, frame)| {
+                let function = self.heap.function(frame.function);
+                let line = function.chunk.get_line(frame.ip.saturating_sub(1));
+                let function = match function.name {
+                    Some(name) => format!("{}()", self.heap.get_str(name)),
+                    None if depth == 0 => "script".to_owned(),
+                    None => "fn()".to_owned(),
+                };
+                TraceFrame { line, function }
+            })
+            .collect();
+
+        self.reset_stack();
+        InterpretError::Runtime(vec![Diagnostic {
+            kind: ErrorKind::Runtime,
+            message: msg.to_string(),
+            line: trace.first().map_or(0, |frame| frame.line),
+            column: None,
+            lexeme: None,
+            trace,
+        }])
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.reset();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn print_val(&mut self, val: Value) -> InterpretResult {
+        let str = self.heap.format_value(val);
+        writeln!(self.output, "{str}").map_err(|e| self.output_error(e))
+    }
+
+    fn output_error(&mut self, e: io::Error) -> InterpretError {
+        self.runtime_error(format!("Failed to write output, {e}"))
+    }
+
+    fn call_tracer(&mut self) -> io::Result<()> {
+        let Some(tracer) = &mut self.tracer else {
+            return Ok(());
+        };
+
+        let frame = self.frames.last().expect("a call frame");
+        let chunk = &self.heap.function(frame.function).chunk;
+        tracer.trace(&TraceEvent::new(
+            chunk,
+            frame.ip,
+            self.stack.as_slice(),
+            &self.heap,
+        ))
+    }
+
+    #[cfg(feature = "profile")]
+    fn profile_instruction(&mut self) {
+        let Some(profile) = &mut self.profile else {
+            return;
+        };
+
+        let frame = self.frames.last().expect("a call frame");
+        let chunk = &self.heap.function(frame.function).chunk;
+        if let Some(op) = chunk.get_op(frame.ip) {
+            profile.record(op, chunk.get_line(frame.ip));
+        }
+    }
+
+    #[cfg(feature = "coverage")]
+    fn cover_instruction(&mut self) {
+        let Some(coverage) = &mut self.coverage else {
+            return;
+        };
+
+        let frame = self.frames.last().expect("a call frame");
+        let line = self.heap.function(frame.function).chunk.get_line(frame.ip);
+        coverage.record(frame.function, frame.ip, line);
+    }
+
+    /// Write the stack and the instruction about to run
+    #[cfg(feature = "debug_trace_execution")]
+    fn trace_instruction(&mut self) -> io::Result<()> {
+        write!(self.output, "\t\t")?;
+        for val in &self.stack {
+            write!(self.output, "[ {val} ]")?;
         }
+        writeln!(self.output)?;
+
+        let frame = self.frames.last().expect("a call frame");
+        let chunk = &self.heap.function(frame.function).chunk;
+        chunk.disassemble_instruction(frame.ip, &mut *self.output)?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
     use super::*;
+    use crate::trace::JsonTracer;
+
+    /// Writer whose contents can still be read after handing it to a Vm
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
     #[test]
-    fn test_vm() {
+    fn test_many_globals() {
         let test = (0..=(u8::MAX as usize + 1))
-            .into_iter()
             .map(|i| format!("var a{i} = \"this is a test {i}\";"))
             .collect::<Vec<_>>()
             .join(" ");
         let test = format!("{test} print a256;");
 
+        let mut vm = Vm::new().with_output(Box::new(io::sink()));
+        assert_eq!(vm.interpret(&test), Ok(()));
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut vm = Vm::new().with_diagnostics(Box::new(io::sink()));
+        let src = r#"
+            fun later() { return defined_after; }
+            var defined_after = 1;
+            var result = later();
+            result = result + 1;
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["result"].as_num(), Some(2.0));
+
+        // Globals persist between calls and keep their slot
+        let slot = vm.globals.slot("result");
+        assert_eq!(vm.interpret("result = result * 10;"), Ok(()));
+        assert_eq!(vm.globals.slot("result"), slot);
+        assert_eq!(vm.globals["result"].as_num(), Some(20.0));
+
+        let message =
+            |result: InterpretResult| result.unwrap_err().diagnostics()[0].message.clone();
+        assert_eq!(
+            message(vm.interpret("print missing;")),
+            "Undefined variable 'missing'"
+        );
+        assert_eq!(
+            message(vm.interpret("missing = 1;")),
+            "Undefined variable 'missing'"
+        );
+        // A slot resolved by an earlier failed script can still be defined
+        assert_eq!(vm.interpret("var missing = 3; print missing;"), Ok(()));
+        assert_eq!(vm.globals["missing"].as_num(), Some(3.0));
+    }
+
+    #[test]
+    fn test_fuel() {
+        let diagnostics = SharedBuffer::default();
+        let mut vm = Vm::new()
+            .with_diagnostics(Box::new(diagnostics.clone()))
+            .with_fuel(1000);
+        assert_eq!(
+            vm.interpret("var i = 0; while (true) { i = i + 1; }"),
+            Err(InterpretError::OutOfFuel)
+        );
+        assert_eq!(vm.fuel(), Some(0));
+        let stopped_at = vm.globals["i"].as_num().unwrap();
+        assert!(stopped_at > 0.0);
+
+        // Resuming without more fuel stops straight away
+        assert_eq!(vm.resume(), Err(InterpretError::OutOfFuel));
+        vm.add_fuel(1000);
+        assert_eq!(vm.resume(), Err(InterpretError::OutOfFuel));
+        assert!(vm.globals["i"].as_num().unwrap() > stopped_at);
+
+        let src = "var n = 0; for (var i = 0; i < 100; i = i + 1) n = n + i;";
+        assert_eq!(vm.interpret(src), Err(InterpretError::OutOfFuel));
+        while vm.resume() == Err(InterpretError::OutOfFuel) {
+            vm.add_fuel(10);
+        }
+        assert_eq!(vm.globals["n"].as_num(), Some(4950.0));
+        assert!(vm.fuel().unwrap() <= 10);
+
+        // Recursion without loops is stopped at calls
+        vm.set_fuel(Some(500));
+        let src = "fun f(n) { if (n < 2) return n; return f(n - 1) + f(n - 2); } f(30);";
+        assert_eq!(vm.interpret(src), Err(InterpretError::OutOfFuel));
+
+        vm.set_fuel(None);
+        assert_eq!(vm.interpret("var done = f(10);"), Ok(()));
+        assert_eq!(vm.globals["done"].as_num(), Some(55.0));
+        assert_eq!(vm.fuel(), None);
+        assert_eq!(diagnostics.contents(), "");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut vm = Vm::new().with_diagnostics(Box::new(io::sink()));
+        let handle = vm.interrupt_handle();
+        let done = Arc::new(AtomicBool::new(false));
+        let interrupter = std::thread::spawn({
+            let done = done.clone();
+            move || {
+                while !done.load(atomic::Ordering::Relaxed) {
+                    handle.interrupt();
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+            }
+        });
+
+        let err = vm
+            .interpret("fun spin() { while (true) {} } spin();")
+            .unwrap_err();
+        done.store(true, atomic::Ordering::Relaxed);
+        interrupter.join().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Runtime);
+        assert_eq!(err.diagnostics()[0].message, "Interrupted.");
+        assert_eq!(err.diagnostics()[0].trace.len(), 2);
+
+        // The stack was reset and stale interrupts don't stop the next script
+        vm.interrupt_handle().interrupt();
+        assert_eq!(vm.interpret("var i = 0; while (i < 10) i = i + 1;"), Ok(()));
+        assert_eq!(vm.interpret("var after = 1;"), Ok(()));
+        assert_eq!(vm.globals["after"].as_num(), Some(1.0));
+    }
+
+    #[test]
+    fn test_limits() {
+        let error = |vm: &mut Vm, src: &str| {
+            let err = vm.interpret(src).unwrap_err();
+            let diagnostic = &err.diagnostics()[0];
+            (diagnostic.message.clone(), diagnostic.line)
+        };
+
+        let mut vm = Vm::new()
+            .with_diagnostics(Box::new(io::sink()))
+            .with_stack_limit(100);
+        let src = "fun f(a, b, c) {\n var d = a; return f(a, b, c); }\nf(1, 2, 3);";
+        assert_eq!(error(&mut vm, src), ("Stack overflow.".to_owned(), 2));
+        assert!(vm.stack.is_empty());
+
+        // Without any calls
+        let locals: String = (0..200).map(|i| format!("var a{i} = {i};\n")).collect();
+        let src = format!("{{\n{locals}}}");
+        assert_eq!(error(&mut vm, &src), ("Stack overflow.".to_owned(), 101));
+
+        let limited = || {
+            Vm::new()
+                .with_diagnostics(Box::new(io::sink()))
+                .with_memory_limit(64 * 1024)
+        };
+        let src = "var l = [];\nwhile (true) {\n push(l, 1); }";
+        assert_eq!(error(&mut limited(), src), ("Out of memory.".to_owned(), 3));
+        let src = "var m = {};\nvar i = 0;\nwhile (true) { m[i] = i; i = i + 1; }";
+        assert_eq!(error(&mut limited(), src).0, "Out of memory.");
+
+        // Concatenation is refused before going over so the VM stays usable
+        let mut vm = limited();
+        let src = "var s = \"x\";\nwhile (true) s = s + s;";
+        assert_eq!(error(&mut vm, src), ("Out of memory.".to_owned(), 2));
+        assert_eq!(vm.interpret("s = nil;"), Ok(()));
+
+        // Garbage is collected before giving up
+        let src = "for (var i = 0; i < 1000; i = i + 1) { var s = \"${i}\" + \"a\"; }";
+        assert_eq!(vm.interpret(src), Ok(()));
+    }
+
+    #[test]
+    fn test_tracer() {
+        type Event = (OpCode, usize, Vec<String>);
+
+        /// Keeps the op, line and formatted stack of each event
+        struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+        impl Tracer for Recorder {
+            fn trace(&mut self, event: &TraceEvent) -> io::Result<()> {
+                let stack = event.stack.iter().map(|&v| event.heap.format_value(v));
+                let event = (event.op, event.line, stack.collect());
+                self.0.borrow_mut().push(event);
+                Ok(())
+            }
+        }
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new().with_output(Box::new(io::sink()));
+        vm.set_tracer(Some(Box::new(Recorder(events.clone()))));
+        assert_eq!(vm.interpret("var a = \"x\";\nprint a + \"y\";"), Ok(()));
+
+        let events = events.borrow();
+        let ops: Vec<_> = events.iter().map(|(op, ..)| *op).collect();
+        assert_eq!(
+            ops,
+            [
+                OpCode::Constant,
+                OpCode::DefineGlobal,
+                OpCode::GetGlobal,
+                OpCode::Constant,
+                OpCode::Add,
+                OpCode::Print,
+                OpCode::Nil,
+                OpCode::Return,
+            ]
+        );
+        let (_, line, stack) = &events[4];
+        assert_eq!(*line, 2);
+        assert_eq!(stack[1..], ["\"x\"", "\"y\""]);
+
+        // Tracing stops once the tracer is removed
+        vm.set_tracer(None);
+        assert_eq!(vm.interpret("print 1;"), Ok(()));
+        assert_eq!(events.len(), 8);
+
+        let json = SharedBuffer::default();
+        vm.set_tracer(Some(Box::new(JsonTracer::new(json.clone()))));
+        assert_eq!(vm.interpret("print 2;"), Ok(()));
+        let first = json.contents().lines().next().unwrap().to_owned();
+        assert_eq!(
+            first,
+            r#"{"offset":0,"op":"Constant","operands":[0],"line":1,"stack":["<fn>"]}"#
+        );
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn test_profile() {
+        let mut vm = Vm::new().with_output(Box::new(io::sink())).with_profiler();
+        let src = "var total = 0;\nfor (var i = 0; i < 10; i = i + 1) {\n  total = total + i;\n}\nprint total;";
+        assert_eq!(vm.interpret(src), Ok(()));
+
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.op_count(OpCode::Loop), 20);
+        assert_eq!(profile.op_count(OpCode::Print), 1);
+        assert_eq!(profile.op_count(OpCode::Return), 1);
+        // Get total, get i, add, set total, pop each iteration
+        assert_eq!(profile.line(3).unwrap().count, 50);
+        // Get total and print, then the implicit return
+        assert_eq!(profile.line(5).unwrap().count, 4);
+        assert!(profile.line(6).is_none());
+
+        let mut report = Vec::new();
+        profile.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let ops = report.find("== opcodes ==").unwrap();
+        let lines = report.find("== lines ==").unwrap();
+        assert!(ops < lines);
+        assert!(report[ops..lines].contains("Loop"));
+    }
+
+    #[cfg(feature = "coverage")]
+    #[test]
+    fn test_coverage() {
+        let mut vm = Vm::new().with_output(Box::new(io::sink())).with_coverage();
+        let src = "fun f(n) {\n  if (n > 1) {\n    return n;\n  }\n  return 0;\n}\n\
+                   var i = 0;\nwhile (i < 3) {\n  i = i + 1;\n}\nprint f(2);\n";
+        assert_eq!(vm.interpret(src), Ok(()));
+
+        let coverage = vm.coverage().unwrap();
+        let hits = coverage.line_hits();
+        assert_eq!(hits[&2], 1);
+        assert_eq!(hits[&3], 1);
+        // Jumping over the missing else and the return after the if
+        assert_eq!(hits[&4], 0);
+        assert_eq!(hits[&5], 0);
+        assert_eq!(hits[&8], 4);
+        assert_eq!(hits[&9], 3);
+        // The function's closure is created once its body is compiled
+        assert!(!hits.contains_key(&1));
+        assert_eq!(hits[&6], 1);
+
+        let mut lcov = Vec::new();
+        coverage.write_lcov("test.lox", &mut lcov).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:test.lox\nDA:2,1\n"));
+        assert!(lcov.contains("DA:5,0\n"));
+        assert!(lcov.ends_with(&format!(
+            "LF:{}\nLH:{}\nend_of_record\n",
+            hits.len(),
+            hits.len() - 2
+        )));
+
+        let mut summary = Vec::new();
+        coverage.write_summary("test.lox", &mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        assert!(summary.ends_with("not run: 4, 5\n"));
+    }
+
+    #[cfg(feature = "coverage")]
+    #[test]
+    fn test_coverage_after_collection() {
+        let mut vm = Vm::new().with_output(Box::new(io::sink())).with_coverage();
+        assert_eq!(
+            vm.interpret("print 0; print 0; print 0; print 0; print 0;\nprint 1;"),
+            Ok(())
+        );
+        vm.collect_garbage();
+        // Were the first script's function collected, f would take its
+        // closure's slot and this script's function would take its slot
+        assert_eq!(vm.interpret("fun f() {}\nprint 1;"), Ok(()));
+
+        let hits = vm.coverage().unwrap().line_hits();
+        assert_eq!(hits[&1], 2);
+        assert_eq!(hits[&2], 2);
+    }
+
+    #[test]
+    fn test_diagnostics() {
         let mut vm = Vm::new();
-        println!("{:?}", vm.interpret(&test));
-        panic!()
+        let err = vm.interpret("var a = 1;\n  a = ;\nprint 1").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Compile);
+
+        let diagnostics = err.diagnostics();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].message, "Expect expression.");
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!(diagnostics[0].column, Some(7));
+        assert_eq!(diagnostics[0].lexeme.as_deref(), Some(";"));
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "[line 3] Error at end: Expect ';' after value."
+        );
+
+        let err = vm.interpret("\"a").unwrap_err();
+        assert_eq!(err.diagnostics()[0].lexeme, None);
+        assert_eq!(
+            err.diagnostics()[0].to_string(),
+            "[line 1] Error: Unterminated string."
+        );
+
+        let src = "fun f() {\n  return -nil;\n}\nf();";
+        let err = vm.interpret(src).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Runtime);
+
+        let diagnostic = &err.diagnostics()[0];
+        assert_eq!(diagnostic.message, "Operand must be a number.");
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(
+            diagnostic.to_string(),
+            "Operand must be a number.\n[line 2] in f()\n[line 4] in script"
+        );
+    }
+
+    #[test]
+    fn test_output() {
+        let (output, diagnostics) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut vm = Vm::new()
+            .with_output(Box::new(output.clone()))
+            .with_diagnostics(Box::new(diagnostics.clone()));
+
+        assert_eq!(vm.interpret("print 1 + 2; print \"a\";"), Ok(()));
+        assert!(vm.interpret("print -nil;").is_err());
+        assert!(vm.interpret("print;").is_err());
+
+        // Debug features write disassembly to the same sink
+        #[cfg(not(any(feature = "debug_print_code", feature = "debug_trace_execution")))]
+        assert_eq!(output.contents(), "3\n\"a\"\n");
+        assert_eq!(
+            diagnostics.contents(),
+            "Operand must be a number.\n[line 1] in script\n\
+             [line 1] Error at ;: Expect expression.\n"
+        );
+    }
+
+    #[test]
+    fn test_optimizer() {
+        let src = r#"
+            fun count(n) {
+                var total = 0;
+                for (var i = 0; i < n; i = i + 1) {
+                    if (i != 2 and i >= 1) total = total + 1;
+                    if (i <= 3) continue;
+                    var s = "s";
+                    s = s + "!";
+                }
+                return total;
+            }
+            print count(10);
+            var nan = 0 / 0;
+            print nan >= 1;
+            print nan <= 1;
+            {
+                var a = "a";
+                print a + "b";
+                a = a + nil;
+            }
+        "#;
+
+        let run = |optimize| {
+            let (output, diagnostics) = (SharedBuffer::default(), SharedBuffer::default());
+            let mut vm = Vm::new()
+                .with_output(Box::new(output.clone()))
+                .with_diagnostics(Box::new(diagnostics.clone()))
+                .with_optimizer(optimize);
+            let err = vm.interpret(src).unwrap_err();
+            (output.contents(), err)
+        };
+
+        let (output, err) = run(true);
+        let (unoptimized_output, unoptimized_err) = run(false);
+        assert_eq!(err, unoptimized_err);
+        // Debug features write the differing code to the same sink
+        if !cfg!(any(
+            feature = "debug_print_code",
+            feature = "debug_trace_execution"
+        )) {
+            assert_eq!(output, unoptimized_output);
+            assert_eq!(output, "8\ntrue\ntrue\n\"ab\"\n");
+        }
+        assert_eq!(err.diagnostics()[0].line, 19);
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let run = |src: &str| {
+            let output = SharedBuffer::default();
+            let mut vm = Vm::new()
+                .with_output(Box::new(output.clone()))
+                .with_diagnostics(Box::new(io::sink()));
+            let result = vm.interpret(src);
+            (output.contents(), result.map_err(|e| e.kind()))
+        };
+
+        // Folded literals give the same results as the VM computing them
+        let folded = run(r#"
+            print 60 * 60 * 24;
+            print 1 / 0;
+            print -1 / 0 < 0;
+            print 0 / 0 >= 1;
+            print "a" + "b" == "ab";
+            print !nil != !0;
+            print 0.1 + 0.2 == 0.3;
+        "#);
+        let computed = run(r#"
+            var a = 60; var b = 0; var c = 1; var d = "a"; var z = nil;
+            print a * a * 24;
+            print c / b;
+            print -c / b < b;
+            print b / b >= c;
+            print d + "b" == "ab";
+            print !z != !b;
+            print 0.1 + 0.2 == 0.3 + b;
+        "#);
+        assert_eq!(folded.1, computed.1);
+        // Debug features write the differing code to the same sink
+        if !cfg!(any(
+            feature = "debug_print_code",
+            feature = "debug_trace_execution"
+        )) {
+            assert_eq!(folded.0, computed.0);
+            assert_eq!(folded.0, "86400\ninf\ntrue\ntrue\ntrue\ntrue\nfalse\n");
+        }
+
+        assert_eq!(run("print -\"a\";").1, Err(ErrorKind::Runtime));
+        assert_eq!(run("print 1 + nil;").1, Err(ErrorKind::Runtime));
+        assert_eq!(run("print true > false;").1, Err(ErrorKind::Runtime));
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut vm = Vm::new();
+        let src = r#"
+            fun add(a, b) { return a + b; }
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            fun nothing() {}
+            var sum = add(1, 2);
+            var f = fib(10);
+            var n = nothing();
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["sum"].as_num(), Some(3.0));
+        assert_eq!(vm.globals["f"].as_num(), Some(55.0));
+        assert!(vm.globals["n"].is_nil());
+
+        assert!(matches!(
+            vm.interpret("add(1);"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("sum();"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("return 1;"),
+            Err(InterpretError::Compile(..))
+        ));
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let mut vm = Vm::new();
+        let src = r#"
+            var evens = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                var odd = i == 1 or i == 3 or i == 5;
+                if (i == 7) break;
+                if (odd) continue;
+                evens = evens + 1;
+            }
+
+            var pairs = 0;
+            var n = 0;
+            while (n < 5) {
+                n = n + 1;
+                var m = 0;
+                while (true) {
+                    var skip = m;
+                    m = m + 1;
+                    if (m > n) break;
+                    if (skip == 0) continue;
+                    pairs = pairs + 1;
+                }
+                if (n == 3) continue;
+            }
+
+            var captured = nil;
+            for (var j = 0; j < 5; j = j + 1) {
+                fun get() { return j; }
+                captured = get;
+                if (j == 2) break;
+            }
+            var last = captured();
+
+            var outer = "kept";
+            {
+                var stack = 1;
+                while (true) { var x = 1; break; }
+                outer = outer + str(stack);
+            }
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["evens"].as_num(), Some(4.0));
+        assert_eq!(vm.globals["pairs"].as_num(), Some(10.0));
+        assert_eq!(vm.globals["last"].as_num(), Some(2.0));
+        assert_eq!(vm.heap.to_string(vm.globals["outer"]), "kept1");
+
+        assert!(matches!(
+            vm.interpret("break;"),
+            Err(InterpretError::Compile(..))
+        ));
+        assert!(matches!(
+            vm.interpret("fun f() { continue; } while (true) f();"),
+            Err(InterpretError::Compile(..))
+        ));
+    }
+
+    #[test]
+    fn test_closures() {
+        let mut vm = Vm::new();
+        let src = r#"
+            fun make_counter() {
+                var count = 0;
+                return fun () {
+                    count = count + 1;
+                    return count;
+                };
+            }
+            var counter = make_counter();
+            counter();
+            var second = counter();
+
+            var getters = nil;
+            {
+                var shared = "before";
+                fun get() { return shared; }
+                shared = "after";
+                getters = get;
+            }
+            var closed = getters();
+
+            var first = nil;
+            var last = nil;
+            for (var i = 0; i < 3; i = i + 1) {
+                fun capture() { return i; }
+                if (i == 0) first = capture;
+                last = capture;
+            }
+            var first_i = first();
+            var last_i = last();
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["second"].as_num(), Some(2.0));
+        assert_eq!(vm.globals["first_i"].as_num(), Some(0.0));
+        assert_eq!(vm.globals["last_i"].as_num(), Some(2.0));
+
+        let closed = vm.globals["closed"].as_str().unwrap();
+        assert_eq!(vm.heap.get_str(closed), "after");
+    }
+
+    #[test]
+    fn test_classes() {
+        let mut vm = Vm::new();
+        let src = r#"
+            class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                }
+
+                sum() { return this.x + this.y; }
+                scale(by) {
+                    this.x = this.x * by;
+                    this.y = this.y * by;
+                    return this;
+                }
+            }
+
+            var p = Point(1, 2);
+            var sum = p.scale(2).sum();
+            var bound = p.sum;
+            p.x = 10;
+            var bound_sum = bound();
+            var again = p.init(0, 0) == p;
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["sum"].as_num(), Some(6.0));
+        assert_eq!(vm.globals["bound_sum"].as_num(), Some(14.0));
+        assert_eq!(vm.globals["again"].as_bool(), Some(true));
+
+        assert!(matches!(
+            vm.interpret("Point(1);"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("p.missing;"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("sum.field = 1;"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("print this;"),
+            Err(InterpretError::Compile(..))
+        ));
+        assert!(matches!(
+            vm.interpret("class A { init() { return 1; } }"),
+            Err(InterpretError::Compile(..))
+        ));
+    }
+
+    #[test]
+    fn test_natives() {
+        let mut vm = Vm::new();
+        vm.define_native("double", 1, |_, args| match args[0].as_num() {
+            Some(n) => Ok(Value::from(n * 2.0)),
+            None => Err("double() expects a number.".to_owned()),
+        });
+
+        let src = r#"
+            var doubled = double(21);
+            var length = len("hello");
+            var converted = num("12.5") + num(1);
+            var as_str = str(1) + str(true) + str(nil);
+            var typ = type(clock());
+            fun f() {}
+            var fn_type = type(f);
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["doubled"].as_num(), Some(42.0));
+        assert_eq!(vm.globals["length"].as_num(), Some(5.0));
+        assert_eq!(vm.globals["converted"].as_num(), Some(13.5));
+
+        let string = |vm: &Vm, name: &str| {
+            let istr = vm.globals[name].as_str().unwrap();
+            vm.heap.get_str(istr).to_owned()
+        };
+        assert_eq!(string(&vm, "as_str"), "1truenil");
+        assert_eq!(string(&vm, "typ"), "number");
+        assert_eq!(string(&vm, "fn_type"), "function");
+
+        assert!(matches!(
+            vm.interpret("double();"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("double(\"a\");"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("len(1);"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("num(\"abc\");"),
+            Err(InterpretError::Runtime(..))
+        ));
+    }
+
+    #[test]
+    fn test_gc() {
+        let mut vm = Vm::new();
+        let src = r#"
+            fun make() {
+                var captured = "captured";
+                return fun () { return captured; };
+            }
+            var keep = make();
+
+            var s = "";
+            for (var i = 0; i < 500; i = i + 1) {
+                s = "x" + s;
+            }
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        vm.collect_garbage();
+
+        // Only the final string survives from the loop
+        assert!(vm.heap.string_count() < 50);
+        assert_eq!(vm.interpret("var kept = keep();"), Ok(()));
+
+        let kept = vm.globals["kept"].as_str().unwrap();
+        assert_eq!(vm.heap.get_str(kept), "captured");
+        let s = vm.globals["s"].as_str().unwrap();
+        assert_eq!(vm.heap.get_str(s).len(), 500);
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut vm = Vm::new();
+        let src = r#"
+            var list = [1, 2, 3,];
+            list[0] = list[1] + list[2];
+            push(list, "four");
+            var popped = pop(list);
+            var nested = [[1], []];
+            push(nested[1], 2);
+            var inner = nested[1][0];
+            var first = list[0];
+            var length = len(list);
+            var empty = len([]);
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["first"].as_num(), Some(5.0));
+        assert_eq!(vm.globals["length"].as_num(), Some(3.0));
+        assert_eq!(vm.globals["empty"].as_num(), Some(0.0));
+        assert_eq!(vm.globals["inner"].as_num(), Some(2.0));
+
+        let popped = vm.globals["popped"].as_str().unwrap();
+        assert_eq!(vm.heap.get_str(popped), "four");
+        assert_eq!(vm.heap.to_string(vm.globals["list"]), "[5, 2, 3]");
+
+        assert!(matches!(
+            vm.interpret("list[-1];"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("list[3] = 1;"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("list[0.5];"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("first[0];"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("pop([]);"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("push(1, 2);"),
+            Err(InterpretError::Runtime(..))
+        ));
+    }
+
+    #[test]
+    fn test_maps() {
+        let mut vm = Vm::new();
+        let src = r#"
+            var m = {"a": 1, 2: "two", true: nil, nil: false,};
+            m["a"] = m["a"] + 10;
+            m["new"] = [1];
+            var a = m["a"];
+            var size = len(m);
+            var has_two = has(m, 2);
+            var has_three = has(m, 3);
+            var k = keys(m);
+            var v = values(m);
+
+            {"statement": "map"};
+            {
+                var block = "still a block";
+            }
+            var empty = len({});
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["a"].as_num(), Some(11.0));
+        assert_eq!(vm.globals["size"].as_num(), Some(5.0));
+        assert_eq!(vm.globals["empty"].as_num(), Some(0.0));
+        assert_eq!(vm.globals["has_two"].as_bool(), Some(true));
+        assert_eq!(vm.globals["has_three"].as_bool(), Some(false));
+        assert_eq!(
+            vm.heap.to_string(vm.globals["k"]),
+            r#"["a", 2, true, nil, "new"]"#
+        );
+        assert_eq!(
+            vm.heap.to_string(vm.globals["m"]),
+            r#"{"a": 11, 2: "two", true: nil, nil: false, "new": [1]}"#
+        );
+
+        assert!(matches!(
+            vm.interpret("m[\"missing\"];"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("m[m] = 1;"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("var bad = {[]: 1};"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("m[0 / 0] = 1;"),
+            Err(InterpretError::Runtime(..))
+        ));
+        assert!(matches!(
+            vm.interpret("keys([]);"),
+            Err(InterpretError::Runtime(..))
+        ));
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mut vm = Vm::new();
+        let src = r#"
+            var a = 1;
+            var b = 2;
+            var sum = "total: ${a + b}!";
+            var mixed = "${true} ${nil} ${"str"} ${[a, b]}";
+            var nested = "outer ${"inner ${a}"} ${{"k": b}["k"]}";
+            var multi = "${a}${b}";
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+
+        let string = |vm: &Vm, name: &str| vm.heap.to_string(vm.globals[name]);
+        assert_eq!(string(&vm, "sum"), "total: 3!");
+        assert_eq!(string(&vm, "mixed"), "true nil str [1, 2]");
+        assert_eq!(string(&vm, "nested"), "outer inner 1 2");
+        assert_eq!(string(&vm, "multi"), "12");
+
+        assert!(matches!(
+            vm.interpret(r#"var bad = "${a + b";"#),
+            Err(InterpretError::Compile(..))
+        ));
+        assert!(matches!(
+            vm.interpret(r#"var bad = "${a b}";"#),
+            Err(InterpretError::Compile(..))
+        ));
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let mut vm = Vm::new();
+        let src = r#"
+            class Shape {
+                init(name) { this.name = name; }
+                area() { return 0; }
+                describe() { return this.area(); }
+            }
+
+            class Square < Shape {
+                init(side) {
+                    super.init("square");
+                    this.side = side;
+                }
+                area() { return this.side * this.side; }
+                base_area() {
+                    var method = super.area;
+                    return method();
+                }
+            }
+
+            var sq = Square(3);
+            var area = sq.describe();
+            var base = sq.base_area();
+            var name = sq.name;
+        "#;
+        assert_eq!(vm.interpret(src), Ok(()));
+        assert_eq!(vm.globals["area"].as_num(), Some(9.0));
+        assert_eq!(vm.globals["base"].as_num(), Some(0.0));
+
+        let name = vm.globals["name"].as_str().unwrap();
+        assert_eq!(vm.heap.get_str(name), "square");
+
+        assert!(matches!(
+            vm.interpret("class A < A {}"),
+            Err(InterpretError::Compile(..))
+        ));
+        assert!(matches!(
+            vm.interpret("class B { f() { super.f(); } }"),
+            Err(InterpretError::Compile(..))
+        ));
+        assert!(matches!(
+            vm.interpret("super.f();"),
+            Err(InterpretError::Compile(..))
+        ));
+        assert!(matches!(
+            vm.interpret("var n = 1; class C < n {}"),
+            Err(InterpretError::Runtime(..))
+        ));
     }
 }